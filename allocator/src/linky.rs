@@ -7,8 +7,18 @@ use spin::{Mutex, MutexGuard};
 
 const DEBUG_FILL: bool = true;
 
+/// Gets more memory for a full heap. Given the fewest bytes that would do, returns the
+/// start and length of a new region, which must be 16 byte aligned.
+pub type Grow = fn(usize) -> Option<(NonNull<u8>, usize)>;
+
+/// Gives back a region `Grow` returned, given its start and length, once nothing in it is
+/// allocated.
+pub type Shrink = fn(NonNull<u8>, usize);
+
 struct Allocator {
     head: Option<NonNull<Link>>,
+    grow: Option<Grow>,
+    shrink: Option<Shrink>,
     memory: [u8; 0x100000],
 }
 
@@ -23,6 +33,9 @@ struct Link {
     next: Option<NonNull<Link>>,
     size: usize,
     state: State,
+    /// Whether this starts a region added by growing the heap. It's never merged into the
+    /// region before it, so the whole region can be given back once it's free.
+    grown: bool,
 }
 
 impl Link {
@@ -46,7 +59,8 @@ impl Debug for Link {
             write!(f, "next: None, ")?;
         }
         write!(f, "size: {}, ", self.size)?;
-        write!(f, "state: {:?} ", self.state)?;
+        write!(f, "state: {:?}, ", self.state)?;
+        write!(f, "grown: {} ", self.grown)?;
         write!(f, "}}")
     }
 }
@@ -56,6 +70,8 @@ impl Allocator {
         Self {
             memory: [0; 0x100000],
             head: None,
+            grow: None,
+            shrink: None,
         }
     }
 
@@ -66,6 +82,7 @@ impl Allocator {
             next: None,
             size: self.memory.len() - size_of::<Link>(),
             state: State::Free,
+            grown: false,
         };
         self.head = Some(NonNull::new_unchecked(head_ptr));
     }
@@ -97,6 +114,7 @@ impl Allocator {
                 next: region.next,
                 size: region.size - size - size_of::<Link>(),
                 state: State::Free,
+                grown: false,
             };
             NonNull::new_unchecked(new_region_ptr)
         };
@@ -107,11 +125,49 @@ impl Allocator {
         region.state = State::Free;
     }
 
+    /// The part of the free `region` to allocate `layout` from, if it fits: `region` itself
+    /// if its memory is aligned enough, or else a new region split off the end of it at the
+    /// first aligned place with room for the new region's link before it.
+    fn fit_region(region: &mut Link, layout: Layout) -> Option<&mut Link> {
+        let start = region.memory() as usize;
+        let end = start + region.size;
+        let mut aligned = start.next_multiple_of(layout.align());
+        while aligned != start && aligned - start < size_of::<Link>() {
+            aligned += layout.align();
+        }
+        if aligned.checked_add(layout.size())? > end {
+            return None;
+        }
+        if aligned == start {
+            return Some(region);
+        }
+
+        let new_region = unsafe {
+            let new_region_ptr = (aligned - size_of::<Link>()) as *mut Link;
+            *new_region_ptr = Link {
+                magic: Link::MAGIC,
+                next: region.next,
+                size: end - aligned,
+                state: State::Free,
+                grown: false,
+            };
+            &mut *new_region_ptr
+        };
+        region.next = Some(NonNull::from(&mut *new_region));
+        region.size = new_region as *mut Link as usize - start;
+        Some(new_region)
+    }
+
     fn try_merge_regions(&mut self, regions: (&mut Link, &mut Link)) {
         let (first, second) = regions;
         if first.state != State::Free || second.state != State::Free {
             return;
         }
+        // Regions added by growing the heap needn't follow on from the one before, and are
+        // kept apart so they can be given back
+        if second.grown {
+            return;
+        }
 
         assert_eq!(first.magic, Link::MAGIC);
         assert_eq!(second.magic, Link::MAGIC);
@@ -136,11 +192,56 @@ impl Allocator {
     }
 
     fn allocate(&mut self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if let Ok(memory) = self.try_allocate(layout) {
+            return Ok(memory);
+        }
+        self.try_grow(layout)?;
+        self.try_allocate(layout)
+    }
+
+    /// Add a region big enough for `layout` to the end of the list.
+    fn try_grow(&mut self, layout: Layout) -> Result<(), AllocError> {
+        let grow = self.grow.ok_or(AllocError)?;
+        // Regions start 16 byte aligned, and anything more takes room to split one off
+        let padding = if layout.align() > 16 {
+            layout.align() + size_of::<Link>()
+        } else {
+            0
+        };
+        let needed = size_of::<Link>() + padding + layout.size();
+        let (memory, len) = grow(needed).ok_or(AllocError)?;
+        assert!(len >= needed);
+
+        let new_region = memory.as_ptr() as *mut Link;
+        unsafe {
+            *new_region = Link {
+                magic: Link::MAGIC,
+                next: None,
+                size: len - size_of::<Link>(),
+                state: State::Free,
+                grown: true,
+            };
+        }
+        let new_region = unsafe { NonNull::new_unchecked(new_region) };
+
+        let mut current = self.head.ok_or(AllocError)?;
+        while let Some(next) = unsafe { current.as_ref() }.next {
+            current = next;
+        }
+        unsafe { current.as_mut() }.next = Some(new_region);
+        Ok(())
+    }
+
+    fn try_allocate(&mut self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
         let mut current = self.head;
         while let Some(mut region) = current {
             let region = unsafe { region.as_mut() };
             assert_eq!(region.magic, Link::MAGIC);
-            if region.size >= layout.size() && region.state == State::Free {
+            let fit = match region.state {
+                State::Free => Self::fit_region(region, layout),
+                State::Allocated => None,
+            };
+            if let Some(region) = fit {
                 // split the region
                 self.split_region(region, layout);
 
@@ -190,6 +291,29 @@ impl Allocator {
             self.try_merge_regions((region, unsafe { next.as_mut() }));
         }
         self.merge_all_regions();
+        self.shrink_free_regions();
+    }
+
+    /// Give back every region added by growing the heap that's entirely free. Merging
+    /// leaves such a region as a single free link, followed by the next grown one if any.
+    fn shrink_free_regions(&mut self) {
+        let Some(shrink) = self.shrink else {
+            return;
+        };
+        // The head is in the static memory, so it's never given back
+        let Some(mut prev) = self.head else {
+            return;
+        };
+        while let Some(current) = unsafe { prev.as_ref() }.next {
+            let region = unsafe { current.as_ref() };
+            let whole = region.next.is_none_or(|next| unsafe { next.as_ref() }.grown);
+            if region.grown && region.state == State::Free && whole {
+                unsafe { prev.as_mut() }.next = region.next;
+                shrink(current.cast(), region.size + size_of::<Link>());
+            } else {
+                prev = current;
+            }
+        }
     }
 }
 
@@ -216,6 +340,16 @@ impl LockedAllocator {
     pub unsafe fn init(&self) {
         self.lock().init();
     }
+
+    /// Let the heap grow with `grow` once the static memory runs out.
+    pub fn set_grow(&self, grow: Grow) {
+        self.lock().grow = Some(grow);
+    }
+
+    /// Give memory the heap grew by back with `shrink` once nothing in it is allocated.
+    pub fn set_shrink(&self, shrink: Shrink) {
+        self.lock().shrink = Some(shrink);
+    }
 }
 
 impl Debug for LockedAllocator {
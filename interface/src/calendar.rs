@@ -0,0 +1,73 @@
+//! Converting between dates in the proleptic Gregorian calendar and days since the Unix
+//! epoch, which is what [`Clock::Realtime`](crate::Clock::Realtime) counts from.
+
+/// Days from 1970-01-01 to the given date in the proleptic Gregorian calendar.
+pub fn days_from_civil(year: i64, month: u64, day: u64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400) as u64;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era as i64 - 719_468
+}
+
+/// The `(year, month, day)` that is `days` after 1970-01-01.
+pub fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as u64;
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Dates and their day numbers, checked against `date -d <date> +%s` / 86400.
+    const KNOWN: &[((u64, u64, u64), u64)] = &[
+        ((1970, 1, 1), 0),
+        ((1970, 12, 31), 364),
+        ((1972, 2, 29), 789),
+        ((1972, 3, 1), 790),
+        ((2000, 2, 29), 11_016),
+        ((2000, 3, 1), 11_017),
+        ((2024, 2, 29), 19_782),
+        ((2100, 2, 28), 47_540),
+        ((2100, 3, 1), 47_541),
+        ((2400, 2, 29), 157_113),
+    ];
+
+    #[test]
+    fn known_dates() {
+        for &((year, month, day), days) in KNOWN {
+            assert_eq!(days_from_civil(year as i64, month, day), days as i64);
+            assert_eq!(civil_from_days(days), (year, month, day));
+        }
+    }
+
+    #[test]
+    fn before_the_epoch() {
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(days_from_civil(1900, 3, 1), -25_508);
+    }
+
+    #[test]
+    fn round_trip() {
+        // Every day through 2500, which covers leap days and skipped century leap days
+        let mut previous = None;
+        for days in 0..days_from_civil(2501, 1, 1) as u64 {
+            let (year, month, day) = civil_from_days(days);
+            assert!((1..=12).contains(&month) && (1..=31).contains(&day));
+            assert_eq!(days_from_civil(year as i64, month, day), days as i64);
+            assert_ne!(previous, Some((year, month, day)));
+            previous = Some((year, month, day));
+        }
+    }
+}
@@ -1,26 +1,425 @@
+//! The Cardinal syscall ABI.
+//!
+//! Syscalls are made with `int 0x80`. On entry:
+//!
+//! - `rax` holds the [`SyscallNumber`]
+//! - `rdi` holds the id of the calling userland task
+//! - `rsi` and `rdx` hold the address and length (in `u64`s) of a buffer the kernel
+//!   fills with the ids of tasks that should be woken
+//! - `r8`, `r9`, `r10`, `r11`, `r12` and `r13` hold up to [`SYSCALL_ARGS`] arguments
+//!
+//! On return `rax` holds the [`ReturnKind`], `rdi` the return value or [`Error`] and
+//! `rdx` the number of task ids written to the wake buffer. All other registers are
+//! preserved.
+//!
+//! Pointer arguments are passed as an address and a length in two consecutive argument
+//! registers, and strings are UTF-8. Arguments a syscall does not use must be zero.
+//!
+//! Kernel objects (sockets, channel endpoints and child processes) are referred to by
+//! handles: small integers that are only meaningful to the process holding them. A
+//! handle can be duplicated with [`Syscall::Duplicate`], moved to another process by
+//! sending it on a channel, and released with [`Syscall::Close`]. An object is closed
+//! once every handle to it is gone, and all of a process's handles are closed when it
+//! exits.
+//!
+//! A new process starts with `rsp` pointing at a zero return address, followed by the
+//! System V initial stack: `argc`, the `argv` and `envp` pointer arrays (each ending in
+//! a null pointer), and the auxiliary vector of [`AuxType`] and value pairs, ending in
+//! [`AuxType::Null`]. The strings are NUL-terminated UTF-8. `rdi` holds the address of
+//! `argc`.
+
 #![no_std]
 
 #[macro_use] mod macros;
+pub mod calendar;
+
+use core::fmt::{Debug, Formatter};
+use core::marker::PhantomData;
+use core::ops::BitOr;
+
+/// Version of the ABI described by this crate, as returned by [`Syscall::AbiVersion`].
+///
+/// This changes whenever an existing syscall number, argument or return encoding changes
+/// meaning. Adding new syscalls does not change it.
+pub const ABI_VERSION: u64 = 4;
+
+/// The exit code of a process the kernel killed for accessing memory it may not.
+pub const EXIT_FAULT: u64 = u64::MAX;
+
+/// Number of argument registers available to a syscall.
+pub const SYSCALL_ARGS: usize = 6;
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct TaskId(pub u64);
 
-#[repr(C)]
 #[derive(Debug)]
 #[non_exhaustive]
 pub enum Syscall<'a> {
+    AbiVersion,
     Print(&'a str),
     Exit(u64),
-    Spawn(&'a str, usize),
+    Spawn(&'a str, &'a [StrRef<'a>]),
 
     Sleep(u64),
 
     DgSocket,
-    DgWrite(u64, &'a [u8]),
+    /// Write a datagram from a socket to the socket bound to an address.
+    DgWrite(u64, u64, &'a [u8]),
+    /// Read the next datagram on a socket, truncated to fit the buffer. Returns the
+    /// datagram's full length, which is more than was read if it was truncated.
     DgRead(u64, &'a mut [u8]),
     DgClose(u64),
     Yield,
+    Wait(u64),
+
+    ChannelCreate(&'a mut [u64; 2]),
+    ChannelSend(u64, &'a [u8], &'a [u64]),
+    ChannelRecv(u64, &'a mut [u8], &'a mut [u64]),
+    ChannelClose(u64),
+
+    Close(u64),
+    Duplicate(u64),
+
+    /// Bind a socket to a nonzero local address.
+    DgBind(u64, u64),
+
+    /// Set the scheduling class of the process behind a handle, or of the calling
+    /// process if the handle is 0. Fails with `PermissionDenied` for a class more urgent
+    /// than the caller's own.
+    SetPriority(u64, Priority),
+    /// Restrict the process behind a handle, or the calling process if the handle is 0,
+    /// to the CPUs in a bitmask.
+    SetAffinity(u64, u64),
+    /// The number of online CPUs.
+    CpuCount,
+    /// Read a clock, in nanoseconds.
+    ClockGet(Clock),
+    /// Report how physical memory is being used.
+    MemoryInfo(&'a mut MemoryInfo),
+    /// Map zeroed memory of at least a length in bytes, rounded up to whole pages, and
+    /// return its address.
+    Map(u64, MapFlags),
+    /// Unmap the pages in an address and length made by `Map`. Pages in the range that
+    /// aren't mapped are skipped.
+    Unmap(u64, u64),
+    /// Change the access allowed to the pages in an address and length, which must all
+    /// have been made by `Map`.
+    Protect(u64, u64, MapFlags),
+    /// Start a copy of the calling process, sharing its memory copy-on-write and holding
+    /// its handles. Returns a handle to the copy, and 0 in the copy itself.
+    Fork,
+}
+
+impl Syscall<'_> {
+    pub fn number(&self) -> SyscallNumber {
+        match self {
+            Syscall::AbiVersion => SyscallNumber::AbiVersion,
+            Syscall::Print(_) => SyscallNumber::Print,
+            Syscall::Exit(_) => SyscallNumber::Exit,
+            Syscall::Spawn(_, _) => SyscallNumber::Spawn,
+            Syscall::Sleep(_) => SyscallNumber::Sleep,
+            Syscall::DgSocket => SyscallNumber::DgSocket,
+            Syscall::DgWrite(_, _, _) => SyscallNumber::DgWrite,
+            Syscall::DgRead(_, _) => SyscallNumber::DgRead,
+            Syscall::DgClose(_) => SyscallNumber::DgClose,
+            Syscall::Yield => SyscallNumber::Yield,
+            Syscall::Wait(_) => SyscallNumber::Wait,
+            Syscall::ChannelCreate(_) => SyscallNumber::ChannelCreate,
+            Syscall::ChannelSend(_, _, _) => SyscallNumber::ChannelSend,
+            Syscall::ChannelRecv(_, _, _) => SyscallNumber::ChannelRecv,
+            Syscall::ChannelClose(_) => SyscallNumber::ChannelClose,
+            Syscall::Close(_) => SyscallNumber::Close,
+            Syscall::Duplicate(_) => SyscallNumber::Duplicate,
+            Syscall::DgBind(_, _) => SyscallNumber::DgBind,
+            Syscall::SetPriority(_, _) => SyscallNumber::SetPriority,
+            Syscall::SetAffinity(_, _) => SyscallNumber::SetAffinity,
+            Syscall::CpuCount => SyscallNumber::CpuCount,
+            Syscall::ClockGet(_) => SyscallNumber::ClockGet,
+            Syscall::MemoryInfo(_) => SyscallNumber::MemoryInfo,
+            Syscall::Map(_, _) => SyscallNumber::Map,
+            Syscall::Unmap(_, _) => SyscallNumber::Unmap,
+            Syscall::Protect(_, _, _) => SyscallNumber::Protect,
+            Syscall::Fork => SyscallNumber::Fork,
+        }
+    }
+
+    pub fn encode(&self) -> SyscallArgs {
+        let number = self.number();
+        match self {
+            Syscall::AbiVersion => SyscallArgs::new(number, &[]),
+            Syscall::Print(s) => SyscallArgs::new(number, &[s.as_ptr() as u64, s.len() as u64]),
+            &Syscall::Exit(code) => SyscallArgs::new(number, &[code]),
+            Syscall::Spawn(name, args) => SyscallArgs::new(
+                number,
+                &[
+                    name.as_ptr() as u64,
+                    name.len() as u64,
+                    args.as_ptr() as u64,
+                    args.len() as u64,
+                ],
+            ),
+            &Syscall::Sleep(usec) => SyscallArgs::new(number, &[usec]),
+            Syscall::DgSocket => SyscallArgs::new(number, &[]),
+            Syscall::DgWrite(sn, dest, buf) => {
+                SyscallArgs::new(number, &[*sn, *dest, buf.as_ptr() as u64, buf.len() as u64])
+            }
+            Syscall::DgRead(sn, buf) => {
+                SyscallArgs::new(number, &[*sn, buf.as_ptr() as u64, buf.len() as u64])
+            }
+            &Syscall::DgClose(sn) => SyscallArgs::new(number, &[sn]),
+            Syscall::Yield => SyscallArgs::new(number, &[]),
+            &Syscall::Wait(child) => SyscallArgs::new(number, &[child]),
+            Syscall::ChannelCreate(ids) => SyscallArgs::new(number, &[ids.as_ptr() as u64]),
+            Syscall::ChannelSend(ch, data, handles) => SyscallArgs::new(
+                number,
+                &[
+                    *ch,
+                    data.as_ptr() as u64,
+                    data.len() as u64,
+                    handles.as_ptr() as u64,
+                    handles.len() as u64,
+                ],
+            ),
+            Syscall::ChannelRecv(ch, buf, handles) => SyscallArgs::new(
+                number,
+                &[
+                    *ch,
+                    buf.as_ptr() as u64,
+                    buf.len() as u64,
+                    handles.as_ptr() as u64,
+                    handles.len() as u64,
+                ],
+            ),
+            &Syscall::ChannelClose(ch) => SyscallArgs::new(number, &[ch]),
+            &Syscall::Close(handle) => SyscallArgs::new(number, &[handle]),
+            &Syscall::Duplicate(handle) => SyscallArgs::new(number, &[handle]),
+            &Syscall::DgBind(sn, addr) => SyscallArgs::new(number, &[sn, addr]),
+            &Syscall::SetPriority(process, priority) => {
+                SyscallArgs::new(number, &[process, priority as u64])
+            }
+            &Syscall::SetAffinity(process, cpus) => SyscallArgs::new(number, &[process, cpus]),
+            Syscall::CpuCount => SyscallArgs::new(number, &[]),
+            &Syscall::ClockGet(clock) => SyscallArgs::new(number, &[clock as u64]),
+            Syscall::MemoryInfo(info) => {
+                SyscallArgs::new(number, &[&**info as *const MemoryInfo as u64])
+            }
+            &Syscall::Map(len, flags) => SyscallArgs::new(number, &[len, flags.0]),
+            &Syscall::Unmap(addr, len) => SyscallArgs::new(number, &[addr, len]),
+            &Syscall::Protect(addr, len, flags) => {
+                SyscallArgs::new(number, &[addr, len, flags.0])
+            }
+            Syscall::Fork => SyscallArgs::new(number, &[]),
+        }
+    }
+}
+
+try_from_enum! {
+    /// Syscall numbers, passed in `rax`. These are part of the ABI; existing numbers must
+    /// never be reused or reordered.
+    pub enum SyscallNumber : u64 {
+        AbiVersion = 0,
+        Print = 1,
+        Exit = 2,
+        Spawn = 3,
+        Sleep = 4,
+        DgSocket = 5,
+        DgWrite = 6,
+        DgRead = 7,
+        DgClose = 8,
+        Yield = 9,
+        Wait = 10,
+        ChannelCreate = 11,
+        ChannelSend = 12,
+        ChannelRecv = 13,
+        ChannelClose = 14,
+        Close = 15,
+        Duplicate = 16,
+        DgBind = 17,
+        SetPriority = 18,
+        SetAffinity = 19,
+        CpuCount = 20,
+        ClockGet = 21,
+        MemoryInfo = 22,
+        Map = 23,
+        Unmap = 24,
+        Protect = 25,
+        Fork = 26,
+    }
+}
+
+/// A string in a slice passed to a syscall, laid out as an address and a length.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct StrRef<'a> {
+    ptr: *const u8,
+    len: usize,
+    _marker: PhantomData<&'a str>,
+}
+
+impl<'a> StrRef<'a> {
+    pub fn as_str(&self) -> &'a str {
+        // SAFETY: a `StrRef` can only be made from a `&'a str`
+        unsafe { core::str::from_utf8_unchecked(core::slice::from_raw_parts(self.ptr, self.len)) }
+    }
+}
+
+impl<'a> From<&'a str> for StrRef<'a> {
+    fn from(value: &'a str) -> Self {
+        Self {
+            ptr: value.as_ptr(),
+            len: value.len(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl Debug for StrRef<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        Debug::fmt(self.as_str(), f)
+    }
+}
+
+try_from_enum! {
+    /// Keys of the auxiliary vector a new process finds on its stack after `envp`. The
+    /// values match the System V ABI.
+    pub enum AuxType : u64 {
+        Null = 0,
+        Phdr = 3,
+        Phent = 4,
+        Phnum = 5,
+        Pagesz = 6,
+        Entry = 9,
+    }
+}
+
+try_from_enum! {
+    /// Scheduling classes, from most to least urgent. Runnable processes in a more urgent
+    /// class run first, but every class is guaranteed some CPU time.
+    pub enum Priority : u64 {
+        Realtime = 0,
+        Normal = 1,
+        Idle = 2,
+    }
+}
+
+try_from_enum! {
+    /// Clocks that can be read with [`Syscall::ClockGet`].
+    pub enum Clock : u64 {
+        /// Time since boot. Never goes backwards.
+        Monotonic = 0,
+        /// Time since the Unix epoch, from the hardware clock at boot.
+        Realtime = 1,
+    }
+}
+
+/// The access allowed to memory made by [`Syscall::Map`]. Mappings must be readable, the
+/// hardware can't make pages that aren't.
+#[repr(transparent)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MapFlags(pub u64);
+
+impl MapFlags {
+    pub const READ: Self = Self(1 << 0);
+    pub const WRITE: Self = Self(1 << 1);
+    pub const EXECUTE: Self = Self(1 << 2);
+
+    const ALL: u64 = Self::READ.0 | Self::WRITE.0 | Self::EXECUTE.0;
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for MapFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl TryFrom<u64> for MapFlags {
+    type Error = ();
+
+    /// Accept only known flags.
+    fn try_from(value: u64) -> Result<Self, ()> {
+        if value & !Self::ALL != 0 {
+            return Err(());
+        }
+        Ok(Self(value))
+    }
+}
+
+/// Physical memory usage, in pages, as filled in by [`Syscall::MemoryInfo`].
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct MemoryInfo {
+    pub page_size: u64,
+    /// Every page of RAM, whatever it holds.
+    pub total: u64,
+    pub free: u64,
+    /// Pages that are never allocated, like firmware tables and the framebuffer.
+    pub reserved: u64,
+    /// Pages holding the kernel image and boot modules.
+    pub kernel_image: u64,
+    /// Pages that can never be freed because their reference count overflowed.
+    pub leaked: u64,
+    /// Allocated pages by owner: kernel data like stacks, page tables, user memory and
+    /// device buffers.
+    pub kernel: u64,
+    pub page_tables: u64,
+    pub user: u64,
+    pub dma: u64,
+}
+
+impl MemoryInfo {
+    /// Pages allocated to any owner.
+    pub fn in_use(&self) -> u64 {
+        self.kernel + self.page_tables + self.user + self.dma
+    }
+}
+
+/// What `ChannelRecv` completes with: the size of the message and how many handles came
+/// with it, packed into one return value.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RecvSize {
+    pub bytes: u32,
+    pub handles: u32,
+}
+
+impl From<u64> for RecvSize {
+    fn from(value: u64) -> Self {
+        Self {
+            bytes: value as u32,
+            handles: (value >> 32) as u32,
+        }
+    }
+}
+
+impl From<RecvSize> for u64 {
+    fn from(value: RecvSize) -> Self {
+        value.bytes as u64 | (value.handles as u64) << 32
+    }
+}
+
+/// A syscall as it is passed in registers.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct SyscallArgs {
+    pub number: u64,
+    pub args: [u64; SYSCALL_ARGS],
+}
+
+impl SyscallArgs {
+    pub fn new(number: SyscallNumber, args: &[u64]) -> Self {
+        let mut res = Self {
+            number: number as u64,
+            args: [0; SYSCALL_ARGS],
+        };
+        res.args[..args.len()].copy_from_slice(args);
+        res
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -30,10 +429,52 @@ pub enum SyscallReturn {
     NotComplete,
 }
 
+impl SyscallReturn {
+    /// The `(rax, rdi)` pair this return is passed to userland in.
+    pub fn encode(self) -> (u64, u64) {
+        match self {
+            SyscallReturn::Complete(v) => (ReturnKind::Complete as u64, v),
+            SyscallReturn::NotComplete => (ReturnKind::NotComplete as u64, 0),
+            SyscallReturn::Error(e) => (ReturnKind::Error as u64, e as u64),
+        }
+    }
+}
+
+try_from_enum! {
+    /// Return kinds, passed back in `rax`.
+    pub enum ReturnKind : u64 {
+        Complete = 0,
+        NotComplete = 1,
+        Error = 2,
+    }
+}
+
+impl From<SyscallReturn> for Result<u64, Error> {
+    /// Convert a finished syscall to a `Result`.
+    ///
+    /// Panics on `NotComplete`, which is never the outcome of a finished syscall.
+    fn from(value: SyscallReturn) -> Self {
+        match value {
+            SyscallReturn::Complete(v) => Ok(v),
+            SyscallReturn::Error(e) => Err(e),
+            SyscallReturn::NotComplete => panic!("syscall has not completed"),
+        }
+    }
+}
+
 try_from_enum! {
+    /// Errors, passed back in `rdi` with `ReturnKind::Error`. These are part of the ABI.
     pub enum Error : u64 {
-        InvalidSyscall,
-        InvalidArgument,
-        NoSuchSocket,
+        InvalidSyscall = 0,
+        InvalidArgument = 1,
+        NoSuchSocket = 2,
+        OutOfMemory = 3,
+        WouldBlock = 4,
+        NotFound = 5,
+        PermissionDenied = 6,
+        BadAddress = 7,
+        Closed = 8,
+        BadHandle = 9,
+        AddressInUse = 10,
     }
 }
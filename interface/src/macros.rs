@@ -1,16 +1,16 @@
 macro_rules! try_from_enum {
-    ($(#[$meta:meta])* $vis:vis enum $name:ident : $type:ty { $($variant:ident,)* }) => {
+    ($(#[$meta:meta])* $vis:vis enum $name:ident : $type:ty { $($(#[$variant_meta:meta])* $variant:ident $(= $value:expr)?,)* }) => {
         $(#[$meta])*
         #[repr($type)]
         #[derive(Debug, Copy, Clone, PartialEq, Eq)]
         $vis enum $name {
-            $($variant,)*
+            $($(#[$variant_meta])* $variant $(= $value)?,)*
         }
 
         impl TryFrom<$type> for $name {
             type Error = ();
 
-            fn try_from(value: $type) -> Result<Self, Self::Error> {
+            fn try_from(value: $type) -> Result<Self, ()> {
                 match value {
                     $(x if x == $name::$variant as $type => Ok($name::$variant),)*
                     _ => Err(()),
@@ -0,0 +1,35 @@
+//! The kernel's clocks, in nanoseconds.
+//!
+//! The monotonic clock counts from early boot. The realtime clock is set from the
+//! hardware clock once at boot and advances with the monotonic clock after that, so it
+//! never jumps.
+
+use crate::arch;
+use crate::print::println;
+use cardinal3_interface::Clock;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Realtime clock value when the monotonic clock read zero.
+static REALTIME_AT_BOOT: AtomicU64 = AtomicU64::new(0);
+
+/// Set the realtime clock from the hardware clock. The monotonic clock must be running.
+pub fn init() {
+    let now = arch::read_rtc() * 1_000_000_000;
+    REALTIME_AT_BOOT.store(now.saturating_sub(monotonic()), Ordering::Relaxed);
+    println!("realtime clock: {}s since the epoch", now / 1_000_000_000);
+}
+
+pub fn monotonic() -> u64 {
+    arch::nanos_since_boot()
+}
+
+pub fn realtime() -> u64 {
+    REALTIME_AT_BOOT.load(Ordering::Relaxed) + monotonic()
+}
+
+pub fn get(clock: Clock) -> u64 {
+    match clock {
+        Clock::Monotonic => monotonic(),
+        Clock::Realtime => realtime(),
+    }
+}
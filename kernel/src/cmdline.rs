@@ -0,0 +1,43 @@
+//! The kernel command line, as `key=value` words separated by whitespace.
+
+use crate::limine;
+use crate::print::println;
+use alloc::string::String;
+use spin::Once;
+
+static CMDLINE: Once<String> = Once::new();
+
+pub fn init() {
+    CMDLINE.call_once(|| {
+        let response = unsafe { *limine::KERNEL_FILE.response.get() };
+        if response.is_null() {
+            return String::new();
+        }
+        let file = unsafe { &*(*response).kernel_file };
+        let cmdline = String::from_utf8_lossy(file.cmdline().to_bytes()).into_owned();
+        println!("kernel cmdline: {:?}", cmdline);
+        cmdline
+    });
+}
+
+/// The value of `key=value` on the command line, or `""` for a bare `key`.
+pub fn get(key: &str) -> Option<&'static str> {
+    let cmdline = CMDLINE.get().expect("cmdline used before init");
+    cmdline.split_whitespace().find_map(|word| match word.split_once('=') {
+        Some((k, value)) if k == key => Some(value),
+        None if word == key => Some(""),
+        _ => None,
+    })
+}
+
+/// Parse the value of `key`, if it's present and valid.
+pub fn parse<T: core::str::FromStr>(key: &str) -> Option<T> {
+    let value = get(key)?;
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            println!("ignoring invalid cmdline value {}={:?}", key, value);
+            None
+        }
+    }
+}
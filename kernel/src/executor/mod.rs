@@ -1,4 +1,5 @@
 use crate::arch;
+use crate::ipi::{self, submit_ipi_to_cpu};
 use crate::per_cpu::PerCpu;
 use alloc::boxed::Box;
 use alloc::collections::{BTreeMap, VecDeque};
@@ -10,6 +11,9 @@ use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
 use spin::Mutex;
 
 pub mod sleep;
+pub mod timeout;
+
+pub use timeout::timeout;
 
 struct Task {
     future: Pin<Box<dyn Future<Output = ()>>>,
@@ -40,6 +44,10 @@ impl Executor {
         self.tasks_to_poll.lock().push_back(id);
     }
 
+    pub fn has_work(&self) -> bool {
+        !self.tasks_to_poll.lock().is_empty()
+    }
+
     pub fn do_work(&mut self) {
         assert!(arch::interrupts_are_disabled());
         loop {
@@ -73,14 +81,20 @@ fn exec_clone(data: *const ()) -> RawWaker {
 }
 
 unsafe fn exec_wake(data: *const ()) {
-    assert!(arch::interrupts_are_disabled());
-    let wd = *(data as *mut WakerData);
-    let executor = PerCpu::executor_for_cpu(wd.cpu);
-    executor.tasks_to_poll.lock().push_back(wd.id);
+    exec_wake_by_ref(data);
+    exec_drop(data);
 }
 
+/// Queue the task on the CPU that owns it, and kick that CPU if it's halted so the
+/// task runs promptly even when the wake came from another CPU.
 unsafe fn exec_wake_by_ref(data: *const ()) {
-    exec_wake(data)
+    assert!(arch::interrupts_are_disabled());
+    let wd = *(data as *const WakerData);
+    let executor = PerCpu::executor_for_cpu(wd.cpu);
+    executor.tasks_to_poll.lock().push_back(wd.id);
+    if wd.cpu != arch::cpu_num() && PerCpu::is_idle(wd.cpu) {
+        ipi::kick(wd.cpu);
+    }
 }
 
 unsafe fn exec_drop(data: *const ()) {
@@ -100,3 +114,13 @@ fn new_waker(id: u64) -> Waker {
 pub fn spawn(future: impl Future<Output = ()> + 'static) {
     PerCpu::executor_mut().spawn(future)
 }
+
+/// Spawn `future` on `cpu`'s executor. Tasks never move between CPUs, so everything it
+/// touches must be safe to use from there.
+pub fn spawn_on(cpu: usize, future: impl Future<Output = ()> + Send + 'static) {
+    if cpu == arch::cpu_num() {
+        spawn(future);
+    } else {
+        submit_ipi_to_cpu(cpu, move || spawn(future));
+    }
+}
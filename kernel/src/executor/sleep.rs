@@ -1,19 +1,31 @@
 use crate::per_cpu::PerCpu;
-use crate::timer;
+use crate::timer::{self, TimerHandle};
 use core::future::Future;
 use core::pin::Pin;
 use core::task::{Context, Poll};
 
+/// Completes once a tick has passed. Until then its waker is registered with the timer,
+/// and the registration is cancelled if it's dropped first.
 #[derive(Debug)]
 #[must_use = "futures do nothing unless you `.await` or poll them"]
-struct Sleep {
+pub struct Sleep {
     until: u64,
+    event: Option<TimerHandle>,
 }
 
 impl Sleep {
     fn new(duration: core::time::Duration) -> Self {
+        // The current tick is already partly over, so wait one more to sleep at least
+        // `duration`.
         Self {
-            until: PerCpu::ticks() + timer::ticks_for(duration),
+            until: PerCpu::ticks() + timer::ticks_for(duration) + 1,
+            event: None,
+        }
+    }
+
+    fn cancel(&mut self) {
+        if let Some(event) = self.event.take() {
+            event.cancel();
         }
     }
 }
@@ -21,17 +33,25 @@ impl Sleep {
 impl Future for Sleep {
     type Output = ();
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        if PerCpu::ticks() > self.until {
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if PerCpu::ticks() >= self.until {
+            self.cancel();
             Poll::Ready(())
         } else {
-            let waker = cx.waker().clone();
-            timer::insert_at(self.until, move || waker.wake_by_ref());
+            // Re-register in case we're polled with a different waker
+            self.cancel();
+            self.event = Some(timer::wake_at(self.until, cx.waker().clone()));
             Poll::Pending
         }
     }
 }
 
-pub fn sleep(duration: core::time::Duration) -> impl Future<Output = ()> {
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+pub fn sleep(duration: core::time::Duration) -> Sleep {
     Sleep::new(duration)
 }
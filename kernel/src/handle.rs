@@ -0,0 +1,101 @@
+//! Per-process handle tables.
+//!
+//! Processes never see the kernel's ids for the objects they use. Each process has a
+//! `HandleTable` mapping small integers to reference-counted `Object`s, so it can only
+//! reach objects it created or was given. Duplicating a handle shares the object, and
+//! the object is closed when the last handle to it (in any process, or in flight in a
+//! channel message) goes away.
+//!
+//! Dropping an `Object` takes the locks of the subsystem it belongs to, so the last
+//! reference must not be dropped while holding `process::ALL` or another subsystem lock.
+
+use crate::net::socket;
+use crate::{ipc, process};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use cardinal3_interface::Error;
+
+#[derive(Debug)]
+pub enum Object {
+    /// A datagram socket, by its id in `socket::ALL`.
+    Socket(u64),
+    /// A channel endpoint, by its id in `ipc`.
+    Channel(u64),
+    /// A child process, by pid.
+    Process(u64),
+}
+
+impl Object {
+    pub fn socket(&self) -> Result<u64, Error> {
+        match *self {
+            Object::Socket(id) => Ok(id),
+            _ => Err(Error::BadHandle),
+        }
+    }
+
+    pub fn channel(&self) -> Result<u64, Error> {
+        match *self {
+            Object::Channel(id) => Ok(id),
+            _ => Err(Error::BadHandle),
+        }
+    }
+
+    pub fn process(&self) -> Result<u64, Error> {
+        match *self {
+            Object::Process(pid) => Ok(pid),
+            _ => Err(Error::BadHandle),
+        }
+    }
+}
+
+impl Drop for Object {
+    fn drop(&mut self) {
+        match *self {
+            Object::Socket(id) => socket::close(id),
+            Object::Channel(id) => ipc::close(id),
+            Object::Process(pid) => process::release(pid),
+        }
+    }
+}
+
+/// Cloning a table shares every object in it, as when a process is forked.
+#[derive(Clone, Default)]
+pub struct HandleTable {
+    objects: BTreeMap<u64, Arc<Object>>,
+}
+
+impl HandleTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `object` under the lowest free handle.
+    pub fn insert(&mut self, object: Arc<Object>) -> u64 {
+        let handle = (1..)
+            .zip(self.objects.keys())
+            .find(|&(expected, &used)| expected != used)
+            .map_or(self.objects.len() as u64 + 1, |(expected, _)| expected);
+        self.objects.insert(handle, object);
+        handle
+    }
+
+    pub fn get(&self, handle: u64) -> Result<Arc<Object>, Error> {
+        self.objects.get(&handle).cloned().ok_or(Error::BadHandle)
+    }
+
+    /// Remove `handle`. The caller must drop the object outside of `process::ALL`.
+    pub fn remove(&mut self, handle: u64) -> Result<Arc<Object>, Error> {
+        self.objects.remove(&handle).ok_or(Error::BadHandle)
+    }
+
+    pub fn duplicate(&mut self, handle: u64) -> Result<u64, Error> {
+        let object = self.get(handle)?;
+        Ok(self.insert(object))
+    }
+
+    /// Take every object out of the table, for when the process exits. The caller must
+    /// drop the result outside of `process::ALL`.
+    pub fn take_all(&mut self) -> BTreeMap<u64, Arc<Object>> {
+        core::mem::take(&mut self.objects)
+    }
+}
@@ -0,0 +1,202 @@
+//! Message passing between processes over channels.
+//!
+//! A channel is a pair of endpoints. Bytes and handles sent on one endpoint are queued
+//! on the other. Handles sent along with a message move from the sender to whichever
+//! process receives the message. Receivers (and senders waiting for room in a full
+//! queue) park their task and are woken through `process::schedule_wakeup`.
+//!
+//! Processes reach endpoints through `handle::Object::Channel`, and an endpoint is
+//! closed when the last handle to it is dropped. An endpoint queued in a message keeps
+//! it open too, so sending one that would end up queued (through any number of queued
+//! messages) on itself is refused: nothing could receive it, and it would never close.
+
+use crate::handle::Object;
+use crate::process;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use cardinal3_interface::Error;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+pub const MAX_MESSAGE: usize = 64 * 1024;
+pub const MAX_HANDLES: usize = 16;
+const QUEUE_LIMIT: usize = 32;
+
+pub struct Message {
+    pub data: Vec<u8>,
+    pub handles: Vec<Arc<Object>>,
+}
+
+struct Endpoint {
+    /// The other end, until it's closed.
+    peer: Option<u64>,
+    queue: VecDeque<Message>,
+    recv_waiters: Vec<(u64, u64)>,
+    send_waiters: Vec<(u64, u64)>,
+}
+
+impl Endpoint {
+    fn new(peer: u64) -> Self {
+        Self {
+            peer: Some(peer),
+            queue: VecDeque::new(),
+            recv_waiters: Vec::new(),
+            send_waiters: Vec::new(),
+        }
+    }
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+static ENDPOINTS: Mutex<BTreeMap<u64, Endpoint>> = Mutex::new(BTreeMap::new());
+
+/// Tasks to wake once `ENDPOINTS` is unlocked, since waking takes the process lock.
+type Wakeups = Vec<(u64, u64)>;
+
+fn wake(wakeups: Wakeups) {
+    for (pid, task_id) in wakeups {
+        process::schedule_wakeup(pid, task_id);
+    }
+}
+
+/// Create a channel and return its two endpoints.
+pub fn create() -> (u64, u64) {
+    let a = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+    let b = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+
+    let mut endpoints = ENDPOINTS.lock();
+    endpoints.insert(a, Endpoint::new(b));
+    endpoints.insert(b, Endpoint::new(a));
+    (a, b)
+}
+
+/// Queue `message` on the other end of `id`.
+///
+/// Returns `Ok(false)` if the queue is full, in which case `task_id` in `pid` is woken
+/// once there's room.
+pub fn send(pid: u64, task_id: u64, id: u64, message: Message) -> Result<bool, Error> {
+    if message.data.len() > MAX_MESSAGE || message.handles.len() > MAX_HANDLES {
+        return Err(Error::InvalidArgument);
+    }
+
+    let wakeups = {
+        let mut endpoints = ENDPOINTS.lock();
+        let endpoint = endpoints.get_mut(&id).ok_or(Error::Closed)?;
+        let peer = endpoint.peer.ok_or(Error::Closed)?;
+
+        // An endpoint queued on itself or its peer could never be closed, and neither
+        // could one whose queue already leads back to `peer`.
+        let makes_cycle = message.handles.iter().any(|object| match **object {
+            Object::Channel(ch) => ch == id || reaches(&endpoints, ch, peer),
+            _ => false,
+        });
+        if makes_cycle {
+            return Err(Error::InvalidArgument);
+        }
+
+        let peer_endpoint = endpoints.get_mut(&peer).unwrap();
+        if peer_endpoint.queue.len() >= QUEUE_LIMIT {
+            let endpoint = endpoints.get_mut(&id).unwrap();
+            if !endpoint.send_waiters.contains(&(pid, task_id)) {
+                endpoint.send_waiters.push((pid, task_id));
+            }
+            return Ok(false);
+        }
+
+        peer_endpoint.queue.push_back(message);
+        core::mem::take(&mut peer_endpoint.recv_waiters)
+    };
+
+    wake(wakeups);
+    Ok(true)
+}
+
+/// Whether `to` is `from`, or an endpoint queued on `from`, or one queued on that, and so
+/// on.
+fn reaches(endpoints: &BTreeMap<u64, Endpoint>, from: u64, to: u64) -> bool {
+    let mut pending = vec![from];
+    let mut seen = BTreeSet::new();
+    while let Some(id) = pending.pop() {
+        if id == to {
+            return true;
+        }
+        if !seen.insert(id) {
+            continue;
+        }
+        if let Some(endpoint) = endpoints.get(&id) {
+            let queued = endpoint.queue.iter().flat_map(|message| &message.handles);
+            pending.extend(queued.filter_map(|object| object.channel().ok()));
+        }
+    }
+    false
+}
+
+/// Take the next message queued on `id`, if it fits in `max_len` bytes and `max_handles`
+/// handles.
+///
+/// Returns `Ok(None)` if nothing is queued, in which case `task_id` in `pid` is woken
+/// when a message arrives.
+pub fn recv(
+    pid: u64,
+    task_id: u64,
+    id: u64,
+    max_len: usize,
+    max_handles: usize,
+) -> Result<Option<Message>, Error> {
+    let (message, wakeups) = {
+        let mut endpoints = ENDPOINTS.lock();
+        let endpoint = endpoints.get_mut(&id).ok_or(Error::Closed)?;
+
+        let Some(next) = endpoint.queue.front() else {
+            if endpoint.peer.is_none() {
+                return Err(Error::Closed);
+            }
+            if !endpoint.recv_waiters.contains(&(pid, task_id)) {
+                endpoint.recv_waiters.push((pid, task_id));
+            }
+            return Ok(None);
+        };
+        if next.data.len() > max_len || next.handles.len() > max_handles {
+            return Err(Error::InvalidArgument);
+        }
+
+        let message = endpoint.queue.pop_front().unwrap();
+
+        // The sender may have been waiting for room in this queue.
+        let wakeups = endpoint
+            .peer
+            .and_then(|peer| endpoints.get_mut(&peer))
+            .map(|peer| core::mem::take(&mut peer.send_waiters))
+            .unwrap_or_default();
+
+        (message, wakeups)
+    };
+
+    wake(wakeups);
+    Ok(Some(message))
+}
+
+/// Close `id`, once the last handle to it is gone. Its peer sees `Error::Closed` once it
+/// has drained its queue.
+pub fn close(id: u64) {
+    let (queue, wakeups) = {
+        let mut endpoints = ENDPOINTS.lock();
+        let Some(endpoint) = endpoints.remove(&id) else {
+            return;
+        };
+
+        let mut wakeups = Vec::new();
+        if let Some(peer) = endpoint.peer.and_then(|peer| endpoints.get_mut(&peer)) {
+            peer.peer = None;
+            wakeups.append(&mut peer.recv_waiters);
+            wakeups.append(&mut peer.send_waiters);
+        }
+        (endpoint.queue, wakeups)
+    };
+
+    // Handles still queued on `id` are dropped here, which may close more endpoints, so
+    // this has to happen with `ENDPOINTS` unlocked.
+    drop(queue);
+    wake(wakeups);
+}
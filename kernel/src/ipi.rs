@@ -1,7 +1,6 @@
 use crate::arch::broadcast_ipi;
 use crate::per_cpu::PerCpu;
 use crate::x86::send_ipi;
-use crate::NUM_CPUS;
 use alloc::boxed::Box;
 
 pub struct IpiFunction {
@@ -28,15 +27,20 @@ pub fn handle_ipi_irq() {
 }
 
 pub fn submit_ipi_to_all_cpus<F: FnOnce() + Clone + 'static>(function: F) {
-    for i in 0..NUM_CPUS {
+    for i in 0..PerCpu::count() {
         PerCpu::submit_ipi(i, function.clone())
     }
 
     broadcast_ipi(129);
 }
 
-#[allow(unused)]
 pub fn submit_ipi_to_cpu<F: FnOnce() + 'static>(cpu: usize, function: F) {
     PerCpu::submit_ipi(cpu, function);
-    send_ipi(cpu as u8, 129);
+    send_ipi(cpu, 129);
+}
+
+/// Interrupt `cpu` with nothing to run, so it notices new work: a halted CPU wakes up
+/// and polls its executor, and a CPU running a process checks whether it should switch.
+pub fn kick(cpu: usize) {
+    send_ipi(cpu, 129);
 }
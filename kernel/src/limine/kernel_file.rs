@@ -0,0 +1,35 @@
+use core::cell::UnsafeCell;
+
+use super::module::LimineFile;
+use super::{LIMINE_MAGIC1, LIMINE_MAGIC2};
+
+#[repr(C)]
+pub struct LimineKernelFile {
+    pub id: [u64; 4],
+    pub revision: u64,
+    pub response: UnsafeCell<*mut LimineKernelFileResponse>,
+}
+
+impl LimineKernelFile {
+    pub const fn new() -> Self {
+        Self {
+            id: [
+                LIMINE_MAGIC1,
+                LIMINE_MAGIC2,
+                0xad97e90e83f1ed67,
+                0x31eb5d1c5ff23b69,
+            ],
+            revision: 0,
+            response: UnsafeCell::new(core::ptr::null_mut()),
+        }
+    }
+}
+
+unsafe impl Sync for LimineKernelFile {}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct LimineKernelFileResponse {
+    pub revision: u64,
+    pub kernel_file: *const LimineFile,
+}
@@ -4,6 +4,7 @@
 
 pub mod boot_info;
 pub mod hhdm;
+pub mod kernel_file;
 pub mod mmap;
 pub mod module;
 pub mod rsdp;
@@ -16,5 +17,6 @@ pub static BOOT_INFO: boot_info::LimineBootInfo = boot_info::LimineBootInfo::new
 pub static HHDM: hhdm::LimineHhdm = hhdm::LimineHhdm::new();
 pub static MMAP: mmap::LimineMmap = mmap::LimineMmap::new();
 pub static RSDP: rsdp::LimineRsdp = rsdp::LimineRsdp::new();
-pub static SMP: smp::LimineSmp = smp::LimineSmp::new(0);
+pub static SMP: smp::LimineSmp = smp::LimineSmp::new(smp::LimineSmp::ENABLE_X2APIC);
 pub static MODULE: module::LimineModule = module::LimineModule::new();
+pub static KERNEL_FILE: kernel_file::LimineKernelFile = kernel_file::LimineKernelFile::new();
@@ -1,5 +1,6 @@
-#![no_std]
-#![no_main]
+// Unit tests build for the host: `cargo test --target x86_64-unknown-linux-gnu`
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
 #![feature(allocator_api)]
 #![feature(const_trait_impl)]
@@ -15,36 +16,45 @@ use cardinal3_allocator as allocator;
 use core::arch::asm;
 use core::time::Duration;
 
+mod clock;
+mod cmdline;
 mod executor;
+mod handle;
+mod ipc;
 mod ipi;
 mod limine;
 mod mem;
+mod modules;
 mod net;
 mod pci;
 mod per_cpu;
 mod pmm;
 mod print;
 mod process;
+mod sync;
 mod syscalls;
 mod timer;
+mod user_mem;
 mod vmm;
 mod x86;
 
 use crate::arch::SERIAL;
 use crate::per_cpu::PerCpu;
-use crate::process::Process;
 use print::{print, println};
 use x86 as arch;
 
-pub const NUM_CPUS: usize = 16;
 pub const START_PROCS: usize = 1;
 
 #[no_mangle]
 pub unsafe extern "C" fn kernel_init() -> ! {
     mem::static_heap_init();
     PerCpu::init();
+    cmdline::init();
     arch::early_system_init();
+    clock::init();
     pmm::init();
+    modules::init();
+    process::sched::init();
     arch::long_jump_cs(kernel_main as usize)
 }
 
@@ -71,9 +81,10 @@ unsafe extern "C" fn kernel_main() -> ! {
         loop {
             let c = SERIAL.read().await;
             match c {
-                b's' => load_and_start_usermode_program(0),
+                b's' => load_and_start_usermode_program(),
                 b'm' => pmm::summary(),
                 b'p' => process::backtrace_all(),
+                b't' => process::sched::dump_stats(),
                 b'b' => arch::breakpoint(),
                 b'B' => executor::spawn(async { arch::breakpoint() }),
                 _ => {}
@@ -96,11 +107,15 @@ unsafe extern "C" fn kernel_main() -> ! {
     //     ]);
     // }
 
+    PerCpu::set_online();
+    wait_for_aps();
+    reclaim_bootloader_memory();
+
     for _ in 0..START_PROCS {
-        load_and_start_usermode_program(0);
+        load_and_start_usermode_program();
     }
 
-    arch::sleep_forever()
+    process::idle_loop()
 }
 
 unsafe extern "C" fn ap_init(info: *const limine::smp::LimineCpuInfo) -> ! {
@@ -116,9 +131,13 @@ unsafe extern "C" fn ap_init(info: *const limine::smp::LimineCpuInfo) -> ! {
 }
 
 unsafe fn ap_main() -> ! {
-    arch::sleep_forever();
+    PerCpu::set_online();
+    // The BSP is waiting for us in `wait_for_aps`
+    ipi::kick(per_cpu::BSP);
+    process::idle_loop()
 }
 
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
     arch::broadcast_ipi(130);
@@ -149,22 +168,55 @@ unsafe fn limine_info() {
     }
 }
 
+/// Start every AP that has a `PerCpu`. Any beyond `per_cpu::MAX_CPUS` stay parked.
 unsafe fn start_aps() {
+    PerCpu::setup_aps();
+
     let smp = &**limine::SMP.response.get();
+    for &cpu in smp.cpus_slice() {
+        let apic_id = (*cpu).lapic_id;
+        if apic_id == smp.bsp_lapic_id || PerCpu::cpu_for_apic_id(apic_id).is_none() {
+            continue;
+        }
+        *(*cpu).goto_address.get_mut() = ap_init;
+    }
+}
 
-    for cpu in smp.cpus_slice().iter().skip(1) {
-        *(**cpu).goto_address.get_mut() = ap_init;
+/// Wait for the APs started by `start_aps` to come online, giving up on any that
+/// haven't after a thousand timer ticks. Each AP kicks the BSP once it's online.
+unsafe fn wait_for_aps() {
+    assert_eq!(arch::cpu_num(), per_cpu::BSP, "APs only wake the BSP when they come online");
+    let expected = PerCpu::count();
+    let deadline = PerCpu::ticks() + 1000;
+    let timeout = timer::insert_at(deadline, || {});
+    loop {
+        arch::disable_interrupts();
+        if PerCpu::online_count() == expected || PerCpu::ticks() >= deadline {
+            break;
+        }
+        arch::enable_interrupts_and_halt();
     }
+    timeout.cancel();
+    println!("{} of {} CPUs online", PerCpu::online_count(), expected);
 }
 
-fn elf_data() -> *const [u8] {
-    unsafe {
-        let mods_info = &**limine::MODULE.response.get();
-        let mod_info = &*mods_info.modules_slice()[0];
-        mod_info.data()
+/// Give the bootloader's memory back once nothing runs from it any more. CPUs that never
+/// came online are still polling their `goto_address` in it, so then it's kept.
+unsafe fn reclaim_bootloader_memory() {
+    let smp = &**limine::SMP.response.get();
+    let parked = smp.cpu_count as usize - PerCpu::online_count();
+    if parked > 0 {
+        println!("keeping bootloader memory, {} CPUs are parked in it", parked);
+        return;
     }
+    pmm::reclaim_bootloader_memory();
+}
+
+/// The first boot module is the program the system starts with.
+fn init_module() -> &'static modules::BootModule {
+    modules::all().first().expect("no init program loaded")
 }
 
-unsafe fn load_and_start_usermode_program(arg: usize) {
-    process::schedule_pid(Process::new(&*elf_data(), arg));
+fn load_and_start_usermode_program() {
+    process::schedule_pid(process::spawn_boot_module(init_module()));
 }
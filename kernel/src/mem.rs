@@ -1,7 +1,7 @@
 use crate::allocator::linky;
 use crate::allocator::linky::LockedAllocator;
 
-#[global_allocator]
+#[cfg_attr(not(test), global_allocator)]
 static ALLOCATOR: LockedAllocator = linky::new();
 
 pub fn static_heap_init() {
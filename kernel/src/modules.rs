@@ -0,0 +1,66 @@
+use crate::limine;
+use crate::print::println;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Once;
+
+/// A file loaded by the bootloader alongside the kernel, like a user program.
+#[derive(Debug)]
+pub struct BootModule {
+    pub path: String,
+    pub cmdline: String,
+    pub data: &'static [u8],
+}
+
+impl BootModule {
+    /// The module path followed by the words of its command line.
+    pub fn argv(&self) -> Vec<String> {
+        core::iter::once(self.path.clone())
+            .chain(self.cmdline.split_whitespace().map(String::from))
+            .collect()
+    }
+
+    /// The last component of the module's path.
+    pub fn name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or(&self.path)
+    }
+}
+
+static MODULES: Once<Vec<BootModule>> = Once::new();
+
+pub fn init() {
+    MODULES.call_once(|| {
+        let response = unsafe { &**limine::MODULE.response.get() };
+        response
+            .modules_slice()
+            .iter()
+            .map(|&file| {
+                let file = unsafe { &*file };
+                let module = BootModule {
+                    path: String::from_utf8_lossy(file.path().to_bytes()).into_owned(),
+                    cmdline: String::from_utf8_lossy(file.cmdline().to_bytes()).into_owned(),
+                    data: unsafe { &*file.data() },
+                };
+                println!(
+                    "module {} ({} bytes) cmdline: {:?}",
+                    module.path,
+                    module.data.len(),
+                    module.cmdline,
+                );
+                module
+            })
+            .collect()
+    });
+}
+
+pub fn all() -> &'static [BootModule] {
+    MODULES.get().expect("modules used before init")
+}
+
+/// Find a module by its full path (`/boot/echo`) or just its name (`echo`).
+pub fn find(name: &str) -> Option<&'static BootModule> {
+    all()
+        .iter()
+        .find(|module| module.path == name)
+        .or_else(|| all().iter().find(|module| module.name() == name))
+}
@@ -1,29 +1,44 @@
+//! Local datagram sockets.
+//!
+//! A socket can be bound to a nonzero local address, and datagrams written to that
+//! address are queued on it. Readers with nothing to read park their task and are woken
+//! through `process::schedule_wakeup` when a datagram arrives.
+
 use crate::net::Packet;
 use crate::per_cpu::PerCpu;
 use crate::print::println;
 use crate::{arch, process};
 use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
 use cardinal3_interface::{Error, SyscallReturn};
-use core::cmp::min;
-use core::future::Future;
-use core::pin::Pin;
 use core::sync::atomic::{AtomicU64, Ordering};
-use core::task::{Context, Poll, Waker};
 use spin::Mutex;
 
+/// The largest datagram a socket accepts.
+pub const MAX_DATAGRAM: usize = 64 * 1024;
+
+/// How many datagrams can be queued on a socket before writes to it fail.
+const QUEUE_LIMIT: usize = 64;
+
 pub struct Socket {
     id: u64,
+    /// The address this socket is bound to, if any.
+    local: Option<u64>,
     dgs: Mutex<VecDeque<Packet>>,
-    futures: Mutex<VecDeque<Waker>>,
+    /// `(pid, task_id)` of readers waiting for a datagram.
+    futures: Mutex<VecDeque<(u64, u64)>>,
 }
 
 static NEXT_ID: AtomicU64 = AtomicU64::new(1);
 
 impl Socket {
+    /// Create a socket and return its id. Processes reach it through
+    /// `handle::Object::Socket`, and it's removed when the last handle is dropped.
     pub fn new() -> u64 {
         let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
         let sock = Self {
             id,
+            local: None,
             dgs: Mutex::new(VecDeque::new()),
             futures: Mutex::new(VecDeque::new()),
         };
@@ -31,14 +46,105 @@ impl Socket {
         ALL.lock().insert(id, sock);
         id
     }
+
+    fn take_waiters(&self) -> Vec<(u64, u64)> {
+        self.futures.lock().drain(..).collect()
+    }
 }
 
 pub static ALL: Mutex<BTreeMap<u64, Socket>> = Mutex::new(BTreeMap::new());
 
-pub fn read(sn: u64, buf: &&mut [u8]) -> SyscallReturn {
-    SyscallReturn::NotComplete
+/// Socket ids by the address they're bound to. Always locked before `ALL`.
+static BOUND: Mutex<BTreeMap<u64, u64>> = Mutex::new(BTreeMap::new());
+
+fn wake(waiters: Vec<(u64, u64)>) {
+    for (pid, task_id) in waiters {
+        process::schedule_wakeup(pid, task_id);
+    }
 }
 
-pub fn write(sn: u64, buf: &[u8]) -> SyscallReturn {
-    SyscallReturn::NotComplete
+/// Bind `sn` to `addr`, so datagrams written to `addr` are queued on it.
+pub fn bind(sn: u64, addr: u64) -> Result<(), Error> {
+    if addr == 0 {
+        return Err(Error::InvalidArgument);
+    }
+
+    let mut bound = BOUND.lock();
+    let mut all = ALL.lock();
+    let socket = all.get_mut(&sn).ok_or(Error::NoSuchSocket)?;
+    if socket.local.is_some() {
+        return Err(Error::InvalidArgument);
+    }
+    if bound.contains_key(&addr) {
+        return Err(Error::AddressInUse);
+    }
+
+    bound.insert(addr, sn);
+    socket.local = Some(addr);
+    Ok(())
+}
+
+/// Remove `sn` once the last handle to it is gone. Queued datagrams are dropped and
+/// waiting readers are woken to find the socket gone.
+pub fn close(sn: u64) {
+    let waiters = {
+        let mut bound = BOUND.lock();
+        let Some(socket) = ALL.lock().remove(&sn) else {
+            return;
+        };
+        if let Some(addr) = socket.local {
+            bound.remove(&addr);
+        }
+        socket.take_waiters()
+    };
+
+    wake(waiters);
+}
+
+/// Take the next datagram queued on `sn`.
+///
+/// Returns `Ok(None)` if nothing is queued, in which case `task_id` in `pid` is woken
+/// when a datagram arrives.
+pub fn read(pid: u64, task_id: u64, sn: u64) -> Result<Option<Packet>, Error> {
+    let all = ALL.lock();
+    let socket = all.get(&sn).ok_or(Error::NoSuchSocket)?;
+
+    let Some(packet) = socket.dgs.lock().pop_front() else {
+        let mut futures = socket.futures.lock();
+        if !futures.contains(&(pid, task_id)) {
+            futures.push_back((pid, task_id));
+        }
+        return Ok(None);
+    };
+    Ok(Some(packet))
+}
+
+/// Queue `buf` as a datagram on the socket bound to `dest`.
+///
+/// Fails with `NoSuchSocket` if nothing is bound to `dest`, and `WouldBlock` if its
+/// queue is full.
+pub fn write(sn: u64, dest: u64, buf: &[u8]) -> Result<SyscallReturn, Error> {
+    if buf.len() > MAX_DATAGRAM {
+        return Err(Error::InvalidArgument);
+    }
+
+    let waiters = {
+        let bound = BOUND.lock();
+        let all = ALL.lock();
+        if !all.contains_key(&sn) {
+            return Err(Error::NoSuchSocket);
+        }
+        let dest = bound.get(&dest).and_then(|id| all.get(id)).ok_or(Error::NoSuchSocket)?;
+
+        let mut dgs = dest.dgs.lock();
+        if dgs.len() >= QUEUE_LIMIT {
+            return Err(Error::WouldBlock);
+        }
+        dgs.push_back(Packet::new(buf));
+        drop(dgs);
+        dest.take_waiters()
+    };
+
+    wake(waiters);
+    Ok(SyscallReturn::Complete(buf.len() as u64))
 }
@@ -100,7 +100,10 @@ impl Rtl8139 {
 
             println!("RTL8139 reset");
 
-            let ring_phy = crate::pmm::alloc_contiguous(16).unwrap();
+            // The NIC takes a 32-bit ring address
+            let ring_phy =
+                crate::pmm::alloc_below(16, crate::pmm::DMA32_LIMIT, crate::pmm::Owner::Dma)
+                    .unwrap();
             let _ring_mapped = arch::direct_map_offset(ring_phy);
 
             self.io_write_u32(0x30, ring_phy as u32); // ring buffer
@@ -1,61 +1,175 @@
 use crate::executor::Executor;
 use crate::ipi::IpiFunction;
+use crate::pmm::PageCache;
+use crate::print::println;
+use crate::process::sched::RunQueue;
 use crate::timer::Timer;
-use crate::x86::cpu_num;
-use crate::{arch, NUM_CPUS};
+use crate::{arch, limine};
 use alloc::collections::VecDeque;
+use alloc::vec::Vec;
 use core::cell::UnsafeCell;
 use core::ops::{Index, IndexMut};
-use spin::{Lazy, Mutex};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::{Mutex, Once};
 
+/// The most CPUs the kernel will bring up, so CPU sets fit in a `u64` bitmask. Any
+/// others are left parked by the bootloader.
+pub const MAX_CPUS: usize = 64;
+
+/// The CPU number of the BSP, which `init` always puts first.
+pub const BSP: usize = 0;
+
+/// Per-CPU state, indexed by logical CPU number. The BSP is CPU 0 and the APs follow in
+/// the order the bootloader lists them, so CPU numbers are dense even when APIC IDs
+/// aren't. Each CPU keeps a pointer to its own `PerCpu` in its GS base.
+#[repr(C)]
 pub struct PerCpu {
+    /// Must stay first, `arch::cpu_local` reads it through GS.
     this: *const UnsafeCell<Self>,
+    cpu: usize,
+    apic_id: u32,
     arch: arch::Cpu,
     timer: Timer,
     executor: Executor,
     running: Option<u64>,
+    /// Whether this CPU is halted waiting for work.
+    idle: AtomicBool,
+    run_queue: Mutex<RunQueue>,
+    page_cache: Mutex<PageCache>,
     ipi_queue: Mutex<VecDeque<IpiFunction>>,
 }
 
 impl PerCpu {
-    fn new() -> Self {
+    fn new(cpu: usize, apic_id: u32) -> Self {
         Self {
             this: core::ptr::null(),
+            cpu,
+            apic_id,
             arch: arch::Cpu::new(),
             timer: Timer::new(),
             executor: Executor::new(),
             running: None,
+            idle: AtomicBool::new(false),
+            run_queue: Mutex::new(RunQueue::new()),
+            page_cache: Mutex::new(PageCache::new()),
             ipi_queue: Mutex::new(VecDeque::new()),
         }
     }
 
+    /// Allocate a `PerCpu` for every CPU the bootloader found and set up the BSP's.
     pub fn init() {
-        for (i, cell) in PER_CPU.cpus.iter().enumerate() {
-            let this = unsafe { &mut *cell.get() };
-            this.this = cell;
-            this.arch.setup(i);
+        let smp = unsafe { &**limine::SMP.response.get() };
+        let mut apic_ids: Vec<u32> = smp
+            .cpus_slice()
+            .iter()
+            .map(|&info| unsafe { (*info).lapic_id })
+            .collect();
+        apic_ids.sort_by_key(|&apic_id| apic_id != smp.bsp_lapic_id);
+        if apic_ids.len() > MAX_CPUS {
+            println!("only using {} of {} CPUs", MAX_CPUS, apic_ids.len());
+            apic_ids.truncate(MAX_CPUS);
+        }
+
+        let container = PER_CPU.call_once(|| PerCpuContainer {
+            cpus: apic_ids
+                .iter()
+                .enumerate()
+                .map(|(cpu, &apic_id)| UnsafeCell::new(Self::new(cpu, apic_id)))
+                .collect(),
+        });
+        for cell in &container.cpus {
+            unsafe { (*cell.get()).this = cell };
+        }
+
+        unsafe { (*container[BSP].get()).arch.setup(BSP) };
+    }
+
+    /// Set up the APs' state. Must be called before they're started, since only its own
+    /// CPU may otherwise touch a `PerCpu` mutably.
+    pub unsafe fn setup_aps() {
+        for cpu in (0..Self::count()).filter(|&cpu| cpu != BSP) {
+            (*PER_CPU.get().unwrap()[cpu].get()).arch.setup(cpu);
         }
     }
 
+    /// Point this CPU's GS base at its `PerCpu`, found by its APIC ID.
+    pub unsafe fn enter() {
+        let apic_id = arch::apic_id();
+        let cpu = Self::cpu_for_apic_id(apic_id).expect("CPU not known to the bootloader");
+        arch::set_cpu_local(PER_CPU.get().unwrap()[cpu].get() as usize);
+    }
+
+    /// The number of CPUs the kernel may bring up, online or not.
+    pub fn count() -> usize {
+        PER_CPU.get().map_or(0, |container| container.cpus.len())
+    }
+
+    pub fn cpu_for_apic_id(apic_id: u32) -> Option<usize> {
+        (0..Self::count()).find(|&cpu| Self::apic_id(cpu) == apic_id)
+    }
+
+    pub fn apic_id(cpu: usize) -> u32 {
+        unsafe { Self::cpu(cpu) }.apic_id
+    }
+
     pub unsafe fn cpu(cpu: usize) -> &'static Self {
-        &*PER_CPU[cpu].get()
+        &*PER_CPU.get().expect("PerCpu used before init")[cpu].get()
     }
 
     // unsafe fn cpu_mut is impossible because we maintain an invariant that mutable access to PerCPU
     // is only performed by the current CPU.
 
     pub fn get() -> &'static Self {
-        unsafe { Self::cpu(cpu_num()) }
+        unsafe { &*(*(arch::cpu_local() as *const UnsafeCell<Self>)).get() }
     }
 
     pub fn get_mut() -> &'static mut Self {
-        unsafe { &mut *PER_CPU[cpu_num()].get() }
+        unsafe { &mut *(*(arch::cpu_local() as *const UnsafeCell<Self>)).get() }
+    }
+
+    /// This CPU's logical number.
+    pub fn cpu_num() -> usize {
+        Self::get().cpu
     }
 
     pub fn executor_for_cpu(cpu: usize) -> &'static Executor {
         &unsafe { Self::cpu(cpu) }.executor
     }
 
+    pub fn run_queue(cpu: usize) -> &'static Mutex<RunQueue> {
+        &unsafe { Self::cpu(cpu) }.run_queue
+    }
+
+    pub fn page_cache() -> &'static Mutex<PageCache> {
+        &Self::get().page_cache
+    }
+
+    /// Mark this CPU as ready to take work.
+    pub fn set_online() {
+        ONLINE.fetch_or(1 << Self::cpu_num(), Ordering::SeqCst);
+    }
+
+    /// Bitmask of the CPUs that are online.
+    pub fn online() -> u64 {
+        ONLINE.load(Ordering::SeqCst)
+    }
+
+    pub fn online_count() -> usize {
+        Self::online().count_ones() as usize
+    }
+
+    pub fn is_online(cpu: usize) -> bool {
+        Self::online() & 1 << cpu != 0
+    }
+
+    pub fn set_idle(idle: bool) {
+        Self::get().idle.store(idle, Ordering::SeqCst);
+    }
+
+    pub fn is_idle(cpu: usize) -> bool {
+        unsafe { Self::cpu(cpu) }.idle.load(Ordering::SeqCst)
+    }
+
     pub fn running() -> Option<u64> {
         Self::get().running
     }
@@ -95,15 +209,7 @@ impl PerCpu {
 // This is all hidden and only exists to impl `Sync` on the UnsafeCell in PerCpu
 
 struct PerCpuContainer {
-    cpus: [UnsafeCell<PerCpu>; NUM_CPUS],
-}
-
-impl PerCpuContainer {
-    fn new() -> Self {
-        Self {
-            cpus: array_init::array_init(|_| UnsafeCell::new(PerCpu::new())),
-        }
-    }
+    cpus: Vec<UnsafeCell<PerCpu>>,
 }
 
 impl Index<usize> for PerCpuContainer {
@@ -125,4 +231,7 @@ impl IndexMut<usize> for PerCpuContainer {
 unsafe impl Send for PerCpuContainer {}
 unsafe impl Sync for PerCpuContainer {}
 
-static PER_CPU: Lazy<PerCpuContainer> = Lazy::new(PerCpuContainer::new);
+static PER_CPU: Once<PerCpuContainer> = Once::new();
+
+/// Bitmask of CPUs that have reached their idle loop.
+static ONLINE: AtomicU64 = AtomicU64::new(0);
@@ -1,26 +1,230 @@
+//! Physical memory.
+//!
+//! Free memory is kept by a buddy allocator: free blocks of 2^order pages sit on one
+//! list per order, linked through the free pages themselves in the direct map, so
+//! allocating and freeing only split or merge a handful of blocks. Single pages also go
+//! through a small per-CPU cache, which keeps most allocations off the buddy lock.
+//!
+//! `PAGE_INFO` tracks what every page is used for, and who owns and how many references
+//! there are to allocated ones.
+//!
+//! Memory the bootloader used is reserved until `reclaim_bootloader_memory`, once the
+//! kernel has copied out everything it needs from it.
+
+use crate::arch::{self, direct_map_offset, PAGE_SIZE};
 use crate::limine;
 use crate::limine::mmap::LimineMmapEntryType;
+use crate::per_cpu::PerCpu;
 use crate::print::println;
+use alloc::vec;
 use alloc::vec::Vec;
+use cardinal3_interface::MemoryInfo;
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use spin::Mutex;
 
+/// What an allocated page is used for, for accounting.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Owner {
+    /// Kernel data structures, like CPU stacks.
+    Kernel,
+    PageTable,
+    User,
+    /// Buffers that devices access directly.
+    Dma,
+}
+
 #[derive(Debug, Copy, Clone)]
 enum PageInfo {
     NoMemory,
     Free,
+    /// Free, but held in a CPU's page cache rather than the buddy allocator.
+    Cached,
     Reserved,
     InUse {
         refcount: u16,
+        owner: Owner,
     },
     Kernel,
-
-    // We don't support refcounting yet, so there's no code for leaking pages.
-    #[allow(dead_code)]
+    /// Had more references than a refcount can count, so it can never be freed.
     Leaked,
 }
 
 static PAGE_INFO: Mutex<Vec<PageInfo>> = Mutex::new(Vec::new());
 
+/// How many pages are free or cached, kept up to date as pages change hands so it can be
+/// read without counting `PAGE_INFO`.
+static FREE_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+/// The largest block the buddy allocator keeps, as a power of two pages (4 MiB).
+const MAX_ORDER: usize = 10;
+
+/// Allocations that must be reachable by devices that only do 32-bit DMA must be below
+/// this address.
+pub const DMA32_LIMIT: u64 = 1 << 32;
+
+/// Marks the end of a free list, and pages that don't start a free block.
+const NONE: usize = usize::MAX;
+const NOT_FREE: u8 = u8::MAX;
+
+/// Links of a free list, stored at the start of the block's first page.
+struct FreeLink {
+    next: usize,
+    prev: usize,
+}
+
+struct Buddy {
+    /// Where page 0 is mapped, so free pages can hold their links. The direct map, except
+    /// in tests.
+    base: usize,
+    /// The first page of a free block on each order's list.
+    lists: [usize; MAX_ORDER + 1],
+    /// For the first page of each free block, its order. `NOT_FREE` for every other page.
+    orders: Vec<u8>,
+}
+
+static BUDDY: Mutex<Buddy> = Mutex::new(Buddy {
+    base: 0,
+    lists: [NONE; MAX_ORDER + 1],
+    orders: Vec::new(),
+});
+
+impl Buddy {
+    fn link(&self, page: usize) -> *mut FreeLink {
+        (self.base + page * PAGE_SIZE) as *mut FreeLink
+    }
+
+    fn push(&mut self, page: usize, order: usize) {
+        let head = self.lists[order];
+        unsafe {
+            self.link(page).write(FreeLink {
+                next: head,
+                prev: NONE,
+            });
+            if head != NONE {
+                (*self.link(head)).prev = page;
+            }
+        }
+        self.lists[order] = page;
+        self.orders[page] = order as u8;
+    }
+
+    fn unlink(&mut self, page: usize, order: usize) {
+        let FreeLink { next, prev } = unsafe { self.link(page).read() };
+        if prev == NONE {
+            self.lists[order] = next;
+        } else {
+            unsafe { (*self.link(prev)).next = next };
+        }
+        if next != NONE {
+            unsafe { (*self.link(next)).prev = prev };
+        }
+        self.orders[page] = NOT_FREE;
+    }
+
+    /// Free the block of 2^`order` pages at `page`, merging it with its buddies.
+    fn free_block(&mut self, mut page: usize, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = page ^ 1 << order;
+            if self.orders.get(buddy) != Some(&(order as u8)) {
+                break;
+            }
+            self.unlink(buddy, order);
+            page = page.min(buddy);
+            order += 1;
+        }
+        self.push(page, order);
+    }
+
+    /// Free an arbitrary range of pages, as the largest aligned blocks that fit.
+    fn free_range(&mut self, mut start: usize, end: usize) {
+        while start < end {
+            let mut order = (start.trailing_zeros() as usize).min(MAX_ORDER);
+            while start + (1 << order) > end {
+                order -= 1;
+            }
+            self.free_block(start, order);
+            start += 1 << order;
+        }
+    }
+
+    /// Take a block of 2^`order` pages that ends at or below page `limit`.
+    fn alloc_block(&mut self, order: usize, limit: usize) -> Option<usize> {
+        for from in order..=MAX_ORDER {
+            // Only the start of a bigger block is kept, so it only has to fit there
+            let mut page = self.lists[from];
+            while page != NONE && page + (1 << order) > limit {
+                page = unsafe { (*self.link(page)).next };
+            }
+            if page == NONE {
+                continue;
+            }
+
+            self.unlink(page, from);
+            for split in (order..from).rev() {
+                self.push(page + (1 << split), split);
+            }
+            return Some(page);
+        }
+        None
+    }
+
+    /// Take `pages` contiguous pages starting at a multiple of `align_pages` pages and
+    /// ending at or below page `limit`.
+    fn alloc_pages(&mut self, pages: usize, align_pages: usize, limit: usize) -> Option<usize> {
+        let order = pages
+            .next_power_of_two()
+            .max(align_pages)
+            .trailing_zeros() as usize;
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        let start = self.alloc_block(order, limit)?;
+        // Give back what's left over of a block bigger than we need
+        self.free_range(start + pages, start + (1 << order));
+        Some(start)
+    }
+}
+
+/// How many free pages each CPU keeps to itself.
+const CACHE_SIZE: usize = 32;
+/// How many pages move between a CPU's cache and the buddy allocator at a time.
+const CACHE_BATCH: usize = CACHE_SIZE / 2;
+
+/// A CPU's cache of free single pages, by page number.
+pub struct PageCache {
+    pages: Vec<usize>,
+}
+
+impl PageCache {
+    pub fn new() -> Self {
+        Self {
+            pages: Vec::with_capacity(CACHE_SIZE),
+        }
+    }
+
+    /// Take up to a batch of pages from `buddy`.
+    fn refill(&mut self, buddy: &mut Buddy, page_info: &mut [PageInfo]) {
+        for _ in 0..CACHE_BATCH {
+            let Some(page) = buddy.alloc_block(0, usize::MAX) else {
+                break;
+            };
+            page_info[page] = PageInfo::Cached;
+            self.pages.push(page);
+        }
+    }
+
+    /// Give a batch of pages back to `buddy`.
+    fn drain(&mut self, buddy: &mut Buddy, page_info: &mut [PageInfo]) {
+        let keep = self.pages.len().saturating_sub(CACHE_BATCH);
+        for page in self.pages.drain(keep..) {
+            page_info[page] = PageInfo::Free;
+            buddy.free_block(page, 0);
+        }
+    }
+}
+
 pub fn init() {
     let mut page_info = PAGE_INFO.lock();
 
@@ -48,23 +252,27 @@ pub fn init() {
     );
 
     page_info.resize(page_count as usize, PageInfo::NoMemory);
+    let mut buddy = BUDDY.lock();
+    buddy.base = direct_map_offset(0);
+    buddy.orders = vec![NOT_FREE; page_count as usize];
 
     for entry in limine_mmap.entries_slice() {
         let entry = unsafe { &**entry };
         let start_page = entry.base / 4096;
-        let end_page = (entry.base + entry.len) / 4096;
+        let end_page = ((entry.base + entry.len) / 4096).min(page_count);
 
         let mut fill_with = |typ| {
             for page in start_page..end_page {
-                if page >= page_count {
-                    break;
-                }
                 page_info[page as usize] = typ;
             }
         };
 
         match entry.typ {
-            LimineMmapEntryType::Usable => fill_with(PageInfo::Free),
+            LimineMmapEntryType::Usable => {
+                fill_with(PageInfo::Free);
+                buddy.free_range(start_page as usize, end_page as usize);
+                FREE_PAGES.fetch_add((end_page - start_page) as usize, Ordering::Relaxed);
+            }
             LimineMmapEntryType::KernelAndModules => fill_with(PageInfo::Kernel),
 
             LimineMmapEntryType::BootloaderReclaimable => fill_with(PageInfo::Reserved),
@@ -77,75 +285,362 @@ pub fn init() {
     }
 }
 
-pub fn alloc() -> Option<u64> {
+/// Free the bootloader's memory, except for the page tables the kernel still runs on.
+///
+/// Everything else the kernel uses from the bootloader (the memory map, module and SMP
+/// info, its stacks) must be copied out or left behind first, and no CPU may still be
+/// running bootloader code.
+pub fn reclaim_bootloader_memory() {
+    // The memory map lives in reclaimable memory too, so read all of it first
+    let limine_mmap = unsafe { &**limine::MMAP.response.get() };
+    let ranges: Vec<Range<usize>> = limine_mmap
+        .entries_slice()
+        .iter()
+        .map(|&entry| unsafe { &*entry })
+        .filter(|entry| entry.typ == LimineMmapEntryType::BootloaderReclaimable)
+        .map(|entry| {
+            (entry.base / 4096) as usize..((entry.base + entry.len) / 4096) as usize
+        })
+        .collect();
+    let tables = arch::kernel_table_pages();
+
+    let mut buddy = BUDDY.lock();
     let mut page_info = PAGE_INFO.lock();
-    for (i, page) in page_info.iter_mut().enumerate() {
-        if let PageInfo::Free = page {
-            *page = PageInfo::InUse { refcount: 1 };
-            return Some((i * 4096) as u64);
+    let mut reclaimed = 0;
+    let mut kept = 0;
+    for range in ranges {
+        let end = range.end.min(page_info.len());
+        let mut run = range.start;
+        for page in range.start..end {
+            if tables.binary_search(&((page * PAGE_SIZE) as u64)).is_ok() {
+                page_info[page] = PageInfo::InUse {
+                    refcount: 1,
+                    owner: Owner::PageTable,
+                };
+                buddy.free_range(run, page);
+                run = page + 1;
+                kept += 1;
+            } else {
+                page_info[page] = PageInfo::Free;
+                reclaimed += 1;
+            }
         }
+        buddy.free_range(run, end);
     }
-    None
+    FREE_PAGES.fetch_add(reclaimed, Ordering::Relaxed);
+    println!(
+        "reclaimed {} KiB of bootloader memory, kept {} page tables",
+        reclaimed * 4,
+        kept
+    );
 }
 
-pub fn alloc_contiguous(pages: usize) -> Option<u64> {
-    let mut page_info = PAGE_INFO.lock();
-    let mut start = 0;
-    let mut count = 0;
-    for (i, page) in page_info.iter_mut().enumerate() {
-        match page {
-            PageInfo::Free => {
-                if count == 0 {
-                    start = i;
-                }
-                count += 1;
-                if count == pages {
-                    for page in &mut page_info[start..start + pages] {
-                        *page = PageInfo::InUse { refcount: 1 };
-                    }
-                    return Some((start * 4096) as u64);
-                }
-            }
-            _ => {
-                count = 0;
-            }
-        }
+fn mark_in_use(page_info: &mut [PageInfo], pages: Range<usize>, owner: Owner) {
+    FREE_PAGES.fetch_sub(pages.len(), Ordering::Relaxed);
+    for page in &mut page_info[pages] {
+        assert!(
+            matches!(page, PageInfo::Free | PageInfo::Cached),
+            "allocating a page that isn't free: {:?}",
+            page
+        );
+        *page = PageInfo::InUse { refcount: 1, owner };
     }
-    None
 }
 
-pub fn free(page: u64) {
-    let mut page_info = PAGE_INFO.lock();
-    let page = (page / 4096) as usize;
-    if let PageInfo::InUse { refcount } = page_info[page] {
-        assert!(refcount > 0);
+/// Allocate one page for `owner`, from this CPU's cache if it has any.
+pub fn alloc(owner: Owner) -> Option<u64> {
+    let mut cache = PerCpu::page_cache().lock();
+    if cache.pages.is_empty() {
+        cache.refill(&mut BUDDY.lock(), &mut PAGE_INFO.lock());
     }
+
+    let page = cache.pages.pop()?;
+    mark_in_use(&mut PAGE_INFO.lock(), page..page + 1, owner);
+    Some((page * PAGE_SIZE) as u64)
+}
+
+pub fn alloc_contiguous(pages: usize, owner: Owner) -> Option<u64> {
+    alloc_constrained(pages, 1, u64::MAX, owner)
+}
+
+/// Allocate `pages` contiguous pages starting at a multiple of `align` bytes.
+pub fn alloc_aligned(pages: usize, align: usize, owner: Owner) -> Option<u64> {
+    assert!(align.is_power_of_two(), "alignment must be a power of two");
+    alloc_constrained(pages, (align / PAGE_SIZE).max(1), u64::MAX, owner)
+}
+
+/// Allocate `pages` contiguous pages that all lie below the physical address `limit`,
+/// for devices that can't address all of memory.
+pub fn alloc_below(pages: usize, limit: u64, owner: Owner) -> Option<u64> {
+    alloc_constrained(pages, 1, limit, owner)
+}
+
+fn alloc_constrained(pages: usize, align_pages: usize, limit: u64, owner: Owner) -> Option<u64> {
+    assert!(pages > 0, "allocating no pages");
+    let limit = (limit / PAGE_SIZE as u64).min(usize::MAX as u64) as usize;
+    let start = BUDDY.lock().alloc_pages(pages, align_pages, limit)?;
+
+    mark_in_use(&mut PAGE_INFO.lock(), start..start + pages, owner);
+    Some((start * PAGE_SIZE) as u64)
+}
+
+/// Take another reference to an allocated page. A page with more references than can be
+/// counted is leaked rather than risk freeing it while it's still used.
+pub fn add_ref(page: u64) {
+    let page = (page / 4096) as usize;
+    let mut page_info = PAGE_INFO.lock();
     page_info[page] = match page_info[page] {
-        PageInfo::InUse { refcount: 1 } => PageInfo::Free,
-        PageInfo::InUse { refcount } => PageInfo::InUse {
-            refcount: refcount - 1,
+        PageInfo::InUse { refcount, .. } if refcount == u16::MAX => PageInfo::Leaked,
+        PageInfo::InUse { refcount, owner } => PageInfo::InUse {
+            refcount: refcount + 1,
+            owner,
         },
         other => other,
+    };
+}
+
+/// Whether `page` may be in use somewhere besides the one reference the caller holds.
+/// Anything but an allocated page with a single reference counts as shared.
+pub fn is_shared(page: u64) -> bool {
+    let page = (page / 4096) as usize;
+    !matches!(PAGE_INFO.lock()[page], PageInfo::InUse { refcount: 1, .. })
+}
+
+/// Drop a reference to `page`, freeing it once there are none left.
+pub fn free(page: u64) {
+    let page = (page / 4096) as usize;
+    {
+        let mut page_info = PAGE_INFO.lock();
+        if let PageInfo::InUse { refcount, .. } = page_info[page] {
+            assert!(refcount > 0);
+        }
+        page_info[page] = match page_info[page] {
+            PageInfo::InUse { refcount: 1, .. } => PageInfo::Cached,
+            PageInfo::InUse { refcount, owner } => PageInfo::InUse {
+                refcount: refcount - 1,
+                owner,
+            },
+            other => other,
+        };
+        if !matches!(page_info[page], PageInfo::Cached) {
+            return;
+        }
     }
+    FREE_PAGES.fetch_add(1, Ordering::Relaxed);
+
+    let mut cache = PerCpu::page_cache().lock();
+    if cache.pages.len() == CACHE_SIZE {
+        cache.drain(&mut BUDDY.lock(), &mut PAGE_INFO.lock());
+    }
+    cache.pages.push(page);
 }
 
-pub fn summary() {
-    let page_info = PAGE_INFO.lock();
-    let mut free = 0;
-    let mut reserved = 0;
-    let mut kernel = 0;
-    let mut leaked = 0;
-    for page in &*page_info {
+/// How many pages are free. Unlike `info`, this doesn't look at every page.
+pub fn free_pages() -> usize {
+    FREE_PAGES.load(Ordering::Relaxed)
+}
+
+/// Count pages by what they're used for.
+pub fn info() -> MemoryInfo {
+    let mut info = MemoryInfo {
+        page_size: PAGE_SIZE as u64,
+        ..MemoryInfo::default()
+    };
+    for page in &*PAGE_INFO.lock() {
         match page {
-            PageInfo::Free => free += 1,
-            PageInfo::Reserved => reserved += 1,
-            PageInfo::Kernel => kernel += 1,
-            PageInfo::Leaked => leaked += 1,
-            _ => {}
+            PageInfo::NoMemory => continue,
+            PageInfo::Free | PageInfo::Cached => info.free += 1,
+            PageInfo::Reserved => info.reserved += 1,
+            PageInfo::Kernel => info.kernel_image += 1,
+            PageInfo::Leaked => info.leaked += 1,
+            PageInfo::InUse { owner, .. } => match owner {
+                Owner::Kernel => info.kernel += 1,
+                Owner::PageTable => info.page_tables += 1,
+                Owner::User => info.user += 1,
+                Owner::Dma => info.dma += 1,
+            },
         }
+        info.total += 1;
     }
+    info
+}
+
+pub fn summary() {
+    let info = info();
+    let cached = PAGE_INFO
+        .lock()
+        .iter()
+        .filter(|page| matches!(page, PageInfo::Cached))
+        .count();
+
+    let buddy = BUDDY.lock();
+    let mut blocks = [0; MAX_ORDER + 1];
+    for &order in &buddy.orders {
+        if order != NOT_FREE {
+            blocks[order as usize] += 1;
+        }
+    }
+    println!(
+        "total: {}, in use: {}, free: {} ({} cached), reserved: {}, kernel image: {}, leaked: {}",
+        info.total,
+        info.in_use(),
+        info.free,
+        cached,
+        info.reserved,
+        info.kernel_image,
+        info.leaked
+    );
     println!(
-        "free: {}, reserved: {}, kernel: {}, leaked: {}",
-        free, reserved, kernel, leaked
+        "in use by kernel: {}, page tables: {}, user: {}, dma: {}",
+        info.kernel, info.page_tables, info.user, info.dma
     );
+    println!("free blocks by order: {:?}", blocks);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A buddy allocator for `pages` pages, none of them free yet, linked through
+    /// `memory`, which must outlive it.
+    fn buddy(pages: usize, memory: &mut Vec<u64>) -> Buddy {
+        *memory = vec![0; pages * PAGE_SIZE / 8];
+        Buddy {
+            base: memory.as_mut_ptr() as usize,
+            lists: [NONE; MAX_ORDER + 1],
+            orders: vec![NOT_FREE; pages],
+        }
+    }
+
+    /// The free blocks as `(page, order)`, by page.
+    fn free_blocks(buddy: &Buddy) -> Vec<(usize, usize)> {
+        (0..buddy.orders.len())
+            .filter(|&page| buddy.orders[page] != NOT_FREE)
+            .map(|page| (page, buddy.orders[page] as usize))
+            .collect()
+    }
+
+    fn free_pages(buddy: &Buddy) -> usize {
+        free_blocks(buddy).iter().map(|&(_, order)| 1 << order).sum()
+    }
+
+    #[test]
+    fn alloc_free_round_trip() {
+        let mut memory = Vec::new();
+        let mut buddy = buddy(64, &mut memory);
+        buddy.free_range(0, 64);
+        assert_eq!(free_blocks(&buddy), [(0, 6)]);
+
+        let mut pages: Vec<usize> =
+            (0..64).map(|_| buddy.alloc_block(0, usize::MAX).unwrap()).collect();
+        assert_eq!(buddy.alloc_block(0, usize::MAX), None);
+        pages.sort();
+        assert_eq!(pages, (0..64).collect::<Vec<_>>());
+
+        for page in pages.into_iter().rev() {
+            buddy.free_block(page, 0);
+        }
+        assert_eq!(free_blocks(&buddy), [(0, 6)]);
+    }
+
+    #[test]
+    fn merges_buddies() {
+        let mut memory = Vec::new();
+        let mut buddy = buddy(16, &mut memory);
+        buddy.free_block(5, 0);
+        buddy.free_block(6, 1);
+        assert_eq!(free_blocks(&buddy), [(5, 0), (6, 1)]);
+        // 4 merges with 5, and 4..6 with 6..8
+        buddy.free_block(4, 0);
+        assert_eq!(free_blocks(&buddy), [(4, 2)]);
+        // 0..4 merges with it, but 8 isn't free so it stops there
+        buddy.free_range(0, 4);
+        assert_eq!(free_blocks(&buddy), [(0, 3)]);
+    }
+
+    #[test]
+    fn free_range_uses_aligned_blocks() {
+        let mut memory = Vec::new();
+        let mut buddy = buddy(32, &mut memory);
+        buddy.free_range(3, 21);
+        assert_eq!(free_blocks(&buddy), [(3, 0), (4, 2), (8, 3), (16, 2), (20, 0)]);
+    }
+
+    #[test]
+    fn alloc_pages_is_aligned() {
+        let mut memory = Vec::new();
+        let mut buddy = buddy(64, &mut memory);
+        buddy.free_range(1, 64);
+
+        let start = buddy.alloc_pages(3, 8, usize::MAX).unwrap();
+        assert_eq!(start % 8, 0);
+        // The rest of the block it came from is free again
+        assert_eq!(free_pages(&buddy), 63 - 3);
+        assert_eq!(buddy.orders[start + 3], 0);
+
+        let start = buddy.alloc_pages(1, 32, usize::MAX).unwrap();
+        assert_eq!(start, 32);
+        assert_eq!(buddy.alloc_pages(1, 32, usize::MAX), None);
+    }
+
+    #[test]
+    fn alloc_pages_stays_below_limit() {
+        let mut memory = Vec::new();
+        let mut buddy = buddy(64, &mut memory);
+        buddy.free_range(4, 64);
+
+        assert_eq!(buddy.alloc_pages(4, 1, 8), Some(4));
+        assert_eq!(buddy.alloc_pages(1, 1, 8), None);
+        assert_eq!(buddy.alloc_pages(2, 1, 10), Some(8));
+        let start = buddy.alloc_pages(1, 1, usize::MAX).unwrap();
+        assert!(start >= 10);
+    }
+
+    #[test]
+    fn alloc_pages_rejects_blocks_past_max_order() {
+        let mut memory = Vec::new();
+        let mut buddy = buddy(4 << MAX_ORDER, &mut memory);
+        buddy.free_range(0, 4 << MAX_ORDER);
+        assert_eq!(buddy.alloc_pages((1 << MAX_ORDER) + 1, 1, usize::MAX), None);
+        assert_eq!(buddy.alloc_pages(1, 2 << MAX_ORDER, usize::MAX), None);
+        let start = buddy.alloc_pages(1 << MAX_ORDER, 1, usize::MAX).unwrap();
+        assert_eq!(start % (1 << MAX_ORDER), 0);
+    }
+
+    #[test]
+    fn page_cache_moves_batches() {
+        let mut memory = Vec::new();
+        let mut buddy = buddy(64, &mut memory);
+        buddy.free_range(0, 64);
+        let mut page_info = vec![PageInfo::Free; 64];
+        let mut cache = PageCache::new();
+
+        cache.refill(&mut buddy, &mut page_info);
+        cache.refill(&mut buddy, &mut page_info);
+        assert_eq!(cache.pages.len(), CACHE_SIZE);
+        assert_eq!(free_pages(&buddy), 64 - CACHE_SIZE);
+        for &page in &cache.pages {
+            assert!(matches!(page_info[page], PageInfo::Cached));
+        }
+
+        cache.drain(&mut buddy, &mut page_info);
+        assert_eq!(cache.pages.len(), CACHE_SIZE - CACHE_BATCH);
+        cache.drain(&mut buddy, &mut page_info);
+        assert!(cache.pages.is_empty());
+        assert!(page_info.iter().all(|page| matches!(page, PageInfo::Free)));
+        assert_eq!(free_blocks(&buddy), [(0, 6)]);
+    }
+
+    #[test]
+    fn page_cache_refill_takes_what_is_left() {
+        let mut memory = Vec::new();
+        let mut buddy = buddy(8, &mut memory);
+        buddy.free_range(0, 5);
+        let mut page_info = vec![PageInfo::Free; 8];
+        let mut cache = PageCache::new();
+
+        cache.refill(&mut buddy, &mut page_info);
+        assert_eq!(cache.pages.len(), 5);
+        assert!(free_blocks(&buddy).is_empty());
+    }
 }
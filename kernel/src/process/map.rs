@@ -1,94 +1,112 @@
-use crate::arch::PageTable;
+use crate::arch;
+use crate::process::vma::{AddressSpace, Backing};
 use crate::vmm::PageFlags;
-use crate::{arch, pmm};
-use core::cmp::min;
+use alloc::vec;
+use alloc::vec::Vec;
+use cardinal3_interface::AuxType;
+use core::ops::Range;
 use elf::endian::LittleEndian;
 use elf::segment::ProgramHeader;
 use elf::ElfBytes;
 
-pub unsafe fn map_elf_into_address_space(
-    elf_data: &[u8],
-    vm_root: *mut PageTable,
-) -> ElfBytes<LittleEndian> {
-    let base = elf_data.as_ptr() as usize;
+/// Add regions for the loadable segments of `elf_data` and the stack to `space`. Their
+/// pages are only filled in as they're touched.
+pub fn map_elf_into_address_space(
+    elf_data: &'static [u8],
+    space: &AddressSpace,
+) -> ElfBytes<'static, LittleEndian> {
     let elf = ElfBytes::minimal_parse(elf_data).expect("Invalid elf");
 
     for ph in elf.segments().unwrap() {
         if ph.p_type == elf::abi::PT_LOAD {
-            if ph.p_flags & elf::abi::PF_W != 0 {
-                create_rw_mapping(&ph, base, vm_root);
-            } else {
-                create_ro_mapping(&ph, base, vm_root);
-            }
+            let range = segment_range(&ph).expect("segment outside of user space");
+            let backing = Backing::File {
+                vaddr: ph.p_vaddr as usize,
+                data: segment_data(elf_data, &ph).expect("segment outside of the file"),
+            };
+            space.add(range, segment_flags(&ph), backing);
         }
     }
 
-    for i in 0..arch::USER_STACK_PAGES {
-        arch::map_in_table(
-            vm_root,
-            arch::USER_STACK_BASE + arch::PAGE_SIZE * i,
-            pmm::alloc().unwrap(),
-            PageFlags::READ | PageFlags::WRITE | PageFlags::USER,
-        )
-    }
+    space.add(
+        arch::USER_STACK_BASE..arch::USER_STACK_TOP,
+        PageFlags::READ | PageFlags::WRITE | PageFlags::USER,
+        Backing::Stack,
+    );
 
     elf
 }
 
-pub fn overlapping_pages(base: usize, len: usize) -> usize {
-    let bottom = base & !arch::PAGE_MASK;
-    let top = (base + len).next_multiple_of(arch::PAGE_SIZE);
-    (top - bottom) / arch::PAGE_SIZE
+fn segment_flags(ph: &ProgramHeader) -> PageFlags {
+    let mut flags = PageFlags::READ | PageFlags::USER;
+    if ph.p_flags & elf::abi::PF_W != 0 {
+        flags |= PageFlags::WRITE;
+    }
+    if ph.p_flags & elf::abi::PF_X != 0 {
+        flags |= PageFlags::EXECUTE;
+    }
+    flags
 }
 
-pub fn mapping_pages(ph: &ProgramHeader, base: usize) -> impl Iterator<Item = (usize, usize)> {
-    let number = overlapping_pages(ph.p_vaddr as usize, ph.p_memsz as usize);
-    let file_base = (base + ph.p_offset as usize) & !arch::PAGE_MASK;
-    let vaddr_base = ph.p_vaddr as usize & !arch::PAGE_MASK;
-
-    (0..number).map(move |n| {
-        (
-            file_base + n * arch::PAGE_SIZE,
-            vaddr_base + n * arch::PAGE_SIZE,
-        )
-    })
+/// The pages a segment is loaded into, if they lie in user space, above the null page and
+/// clear of the stack and its guard page.
+fn segment_range(ph: &ProgramHeader) -> Option<Range<usize>> {
+    let start = ph.p_vaddr as usize & !arch::PAGE_MASK;
+    let end = (ph.p_vaddr as usize)
+        .checked_add(ph.p_memsz as usize)?
+        .checked_next_multiple_of(arch::PAGE_SIZE)?;
+    let stack = arch::USER_STACK_BASE - arch::PAGE_SIZE..arch::USER_STACK_TOP;
+    let fits = start >= arch::PAGE_SIZE
+        && end <= arch::USER_SPACE_END
+        && (end <= stack.start || start >= stack.end);
+    fits.then_some(start..end)
 }
 
-pub unsafe fn create_ro_mapping(ph: &ProgramHeader, base: usize, vm_root: *mut PageTable) {
-    let flags = if ph.p_flags & elf::abi::PF_X != 0 {
-        PageFlags::READ | PageFlags::USER | PageFlags::EXECUTE
-    } else {
-        PageFlags::READ | PageFlags::USER
-    };
-
-    for (file_page, user_page) in mapping_pages(ph, base) {
-        let file_phy = arch::physical_address(file_page).unwrap();
-
-        arch::map_in_table(vm_root, user_page, file_phy, flags);
-    }
+/// The part of the file a segment is loaded from.
+fn segment_data(elf_data: &'static [u8], ph: &ProgramHeader) -> Option<&'static [u8]> {
+    let start = ph.p_offset as usize;
+    elf_data.get(start..start.checked_add(ph.p_filesz as usize)?)
 }
 
-pub unsafe fn create_rw_mapping(ph: &ProgramHeader, base: usize, vm_root: *mut PageTable) {
-    let flags = PageFlags::READ | PageFlags::USER | PageFlags::WRITE;
-    let copy_end = (ph.p_vaddr + ph.p_filesz) as usize;
+/// The auxiliary vector describing `elf` to the program itself.
+pub fn auxv(elf: &ElfBytes<LittleEndian>) -> Vec<(AuxType, u64)> {
+    let mut auxv = vec![
+        (AuxType::Entry, elf.ehdr.e_entry),
+        (AuxType::Pagesz, arch::PAGE_SIZE as u64),
+        (AuxType::Phent, elf.ehdr.e_phentsize as u64),
+        (AuxType::Phnum, elf.ehdr.e_phnum as u64),
+    ];
 
-    for (file_page, user_page) in mapping_pages(ph, base) {
-        let copy_phy = pmm::alloc().unwrap();
-        let copy_mapped = arch::direct_map_offset(copy_phy);
-
-        let copy_len = min(arch::PAGE_SIZE, copy_end.saturating_sub(user_page));
-        if copy_len > 0 {
-            core::ptr::copy_nonoverlapping(
-                file_page as *const u8,
-                copy_mapped as *mut u8,
-                copy_len,
-            );
-        }
-        let zero_len = arch::PAGE_SIZE - copy_len;
-        if zero_len > 0 {
-            core::ptr::write_bytes(copy_mapped as *mut u8, 0, zero_len);
+    // The program headers are only visible to the program if a PT_LOAD segment covers
+    // them, which is usually the first one.
+    let phoff = elf.ehdr.e_phoff;
+    let phdr = elf.segments().unwrap().iter().find_map(|ph| match ph.p_type {
+        elf::abi::PT_PHDR => Some(ph.p_vaddr),
+        elf::abi::PT_LOAD if (ph.p_offset..ph.p_offset + ph.p_filesz).contains(&phoff) => {
+            Some(ph.p_vaddr + phoff - ph.p_offset)
         }
-
-        arch::map_in_table(vm_root, user_page, copy_phy, flags);
+        _ => None,
+    });
+    if let Some(phdr) = phdr {
+        auxv.push((AuxType::Phdr, phdr));
     }
+
+    auxv
+}
+
+pub fn is_loadable_elf(elf_data: &'static [u8]) -> bool {
+    let Ok(elf) = ElfBytes::<LittleEndian>::minimal_parse(elf_data) else {
+        return false;
+    };
+    let Some(segments) = elf.segments() else {
+        return false;
+    };
+    segments
+        .iter()
+        .filter(|ph| ph.p_type == elf::abi::PT_LOAD)
+        .all(|ph| {
+            segment_data(elf_data, &ph).is_some()
+                && segment_range(&ph).is_some()
+                && ph.p_filesz <= ph.p_memsz
+        })
 }
@@ -1,27 +1,61 @@
 mod map;
+pub mod sched;
+mod stack;
+mod vma;
+
+pub use vma::{AddressSpace, Fault};
 
 use crate::arch::{Context, InterruptFrame, PageTable};
-use crate::ipi::submit_ipi_to_all_cpus;
+use crate::handle::HandleTable;
+use crate::ipi::{self, submit_ipi_to_all_cpus};
 use crate::per_cpu::PerCpu;
 use crate::println;
+use crate::user_mem::UserSlice;
 use crate::x86::print_backtrace_from_context;
-use crate::{arch, elf_data};
+use crate::{arch, modules};
 use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
 use core::cmp::min;
 use core::sync::atomic::{AtomicU64, Ordering};
 use spin::Mutex;
-use cardinal3_interface::SyscallReturn;
+use cardinal3_interface::{Error, Priority, SyscallReturn};
 
 pub struct Process {
     context: Context,
-    vm_root: *mut PageTable,
+    /// Taken away once the process has exited, but syscalls still in progress may hold
+    /// on to it until they finish.
+    space: Option<Arc<AddressSpace>>,
     state: ProcessState,
+    sched: sched::Params,
     exit_code: Option<u64>,
     pid: u64,
+    /// Whether some process holds a handle to this one. If not, it's removed as soon as
+    /// it exits rather than kept as a zombie.
+    has_handle: bool,
+    exit_waiters: Vec<(u64, u64)>,
+    env: Vec<String>,
+    handles: HandleTable,
+    /// When the current time slice started, in ticks.
     sched_in: u64,
+    /// When `cpu_ticks` was last brought up to date.
+    accounted_at: u64,
+    /// Timer ticks spent running, including syscalls.
+    cpu_ticks: u64,
+    /// How many times the process has been switched onto a CPU.
+    switches: u64,
+    /// Whether the process is on a run queue, or has been taken off one to run.
+    queued: bool,
+    /// Whether it was picked over more urgent processes to relieve starvation, so it
+    /// isn't preempted for them before its quantum is up.
+    relief: bool,
     on_cpu: Option<usize>,
+    /// The CPU the process last ran on, where it's queued again if its affinity allows.
+    last_cpu: usize,
     tasks_to_wake: VecDeque<u64>,
-    yield_context: Option<*mut [u64]>,
+    yield_context: Option<UserSlice<u64>>,
 }
 
 // Rust is mad because of the PageTable, but we'll never modify that through this object
@@ -34,6 +68,9 @@ pub enum ProcessState {
     Running,
     Waiting,
     Exited,
+    /// Exited and off-CPU, with its address space and handles freed. Kept around until
+    /// the last handle to it is closed, so its exit code can still be collected.
+    Zombie,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -45,47 +82,93 @@ pub enum ProcessDisposition {
 }
 
 impl Process {
-    pub unsafe fn new(elf_data: &'static [u8], arg: usize) -> u64 {
-        let vm_root = arch::new_tree();
-        let efile = map::map_elf_into_address_space(elf_data, vm_root);
+    /// Load `elf_data` into a new process. `argv` and `env` must have passed
+    /// `stack::check_size`. Fails if there's no memory for its page tables or initial
+    /// stack.
+    pub unsafe fn new(
+        elf_data: &'static [u8],
+        argv: &[String],
+        env: Vec<String>,
+        sched: sched::Params,
+        has_handle: bool,
+    ) -> Result<u64, Error> {
+        let space = Arc::new(AddressSpace::new()?);
+        let efile = map::map_elf_into_address_space(elf_data, &space);
         let mut context = Context::new_user(efile.ehdr.e_entry as usize);
 
-        context.set_arg1(arg as u64);
+        let (sp, args) = stack::build_initial_stack(&space, argv, &env, &map::auxv(&efile))?;
+        context.set_user_sp(sp as u64);
+        context.set_arg1(args as u64);
 
-        let pid = NEXT_PID.fetch_add(1, Ordering::SeqCst);
+        let process = Self::from_parts(context, space, env, sched, HandleTable::new(), has_handle);
+        let pid = process.pid;
+        ALL.lock().insert(pid, process);
+        Ok(pid)
+    }
 
-        let process = Self {
+    /// A new runnable process with a fresh pid, not yet in `ALL`.
+    fn from_parts(
+        context: Context,
+        space: Arc<AddressSpace>,
+        env: Vec<String>,
+        sched: sched::Params,
+        handles: HandleTable,
+        has_handle: bool,
+    ) -> Self {
+        Self {
             context,
-            vm_root,
+            space: Some(space),
             state: ProcessState::Running,
+            sched,
             exit_code: None,
-            pid,
+            pid: NEXT_PID.fetch_add(1, Ordering::SeqCst),
+            has_handle,
+            exit_waiters: Vec::new(),
+            env,
+            handles,
             sched_in: 0,
+            accounted_at: 0,
+            cpu_ticks: 0,
+            switches: 0,
+            queued: false,
+            relief: false,
             on_cpu: None,
+            last_cpu: arch::cpu_num(),
             tasks_to_wake: VecDeque::new(),
             yield_context: None,
-        };
-
-        ALL.lock().insert(pid, process);
-        pid
+        }
     }
 
-    pub fn run(id: u64) -> ! {
+    /// Switch to `id` on this CPU. `relief` says whether it was picked to relieve
+    /// starvation.
+    pub fn run(id: u64, relief: bool) -> ! {
         println!("[cpu:{} running pid:{}]", arch::cpu_num(), id);
         let context = with(id, |p| {
             PerCpu::set_running(Some(id));
-            arch::load_tree(p.vm_root);
+            arch::load_tree(p.vm_root());
+            p.queued = false;
+            p.relief = relief;
             p.on_cpu = Some(arch::cpu_num());
-            p.sched_in = PerCpu::ticks();
+            p.last_cpu = arch::cpu_num();
+            p.renew_quantum();
+            p.accounted_at = p.sched_in;
+            p.switches += 1;
             if p.state == ProcessState::Waiting {
                 let Some(yield_context) = p.yield_context else {
                     panic!("waiting with nowhere to put tasks!");
                 };
 
-                let count = p.drain_tasks_to_wake(unsafe { &mut *yield_context });
-                assert!(count > 0, "process woken up with nothing to do!");
-                p.context.frame.set_syscall_return(SyscallReturn::Complete(0));
-                p.context.frame.set_tasks_to_wake_count(count);
+                match p.drain_tasks_to_wake(&yield_context) {
+                    Ok(count) => {
+                        assert!(count > 0, "process woken up with nothing to do!");
+                        p.context.frame.set_syscall_return(SyscallReturn::Complete(0));
+                        p.context.frame.set_tasks_to_wake_count(count);
+                    }
+                    Err(err) => {
+                        p.context.frame.set_syscall_return(SyscallReturn::Error(err));
+                        p.context.frame.set_tasks_to_wake_count(0);
+                    }
+                }
                 p.yield_context = None;
                 p.state = ProcessState::Running;
             }
@@ -97,15 +180,35 @@ impl Process {
     }
 
     pub fn time_expired(&self) -> bool {
-        self.on_cpu.is_some() && self.sched_in + 10 > PerCpu::ticks()
+        PerCpu::ticks() >= self.sched_in + sched::quantum()
+    }
+
+    /// Start a new time slice, and have the timer interrupt when it ends. Must be called
+    /// on the CPU the process is about to run on.
+    pub fn renew_quantum(&mut self) {
+        self.sched_in = PerCpu::ticks();
+        PerCpu::timer_mut().set_preempt_at(Some(self.sched_in + sched::quantum()));
+    }
+
+    /// Charge the process for the ticks since it was last accounted. Must be called on
+    /// the CPU it's running on.
+    pub fn account(&mut self) {
+        let now = PerCpu::ticks();
+        self.cpu_ticks += now.saturating_sub(self.accounted_at);
+        self.accounted_at = now;
     }
 
     pub fn should_run(&self) -> ProcessDisposition {
         match self.state {
-            ProcessState::Exited => ProcessDisposition::NeverAgain,
+            ProcessState::Exited | ProcessState::Zombie => ProcessDisposition::NeverAgain,
             ProcessState::Waiting => ProcessDisposition::NotNow,
             ProcessState::Running => {
-                if self.time_expired() {
+                let cpu = arch::cpu_num();
+                if self.time_expired()
+                    || !self.sched.allows(cpu)
+                    || (!self.relief
+                        && PerCpu::run_queue(cpu).lock().has_more_urgent(self.sched.priority))
+                {
                     ProcessDisposition::TimesUp
                 } else {
                     ProcessDisposition::MayContinue
@@ -119,8 +222,8 @@ impl Process {
         self.exit_code = Some(code);
     }
 
-    pub fn wait(&mut self, frame: &InterruptFrame) {
-        self.yield_context = Some(frame.tasks_to_wake());
+    pub fn wait(&mut self, tasks_to_wake: UserSlice<u64>) {
+        self.yield_context = Some(tasks_to_wake);
         self.state = ProcessState::Waiting;
     }
 
@@ -130,64 +233,168 @@ impl Process {
     }
 
     pub fn vm_root(&self) -> *mut PageTable {
-        self.vm_root
+        self.address_space().root()
+    }
+
+    pub fn address_space(&self) -> &Arc<AddressSpace> {
+        self.space.as_ref().expect("address space used after exit")
+    }
+
+    /// Objects removed from the table must be dropped outside of `ALL`.
+    pub fn handles(&mut self) -> &mut HandleTable {
+        &mut self.handles
     }
 
     pub fn set_context(&mut self, frame: &InterruptFrame) {
         self.context = Context::new(frame);
     }
 
-    pub fn set_on_cpu(&mut self, on_cpu: Option<usize>) {
-        self.on_cpu = on_cpu;
+    /// Whether the process has something to do once it gets a CPU.
+    fn wants_cpu(&self) -> bool {
+        match self.state {
+            ProcessState::Running => true,
+            ProcessState::Waiting => !self.tasks_to_wake.is_empty(),
+            ProcessState::Exited | ProcessState::Zombie => false,
+        }
     }
 
-    pub fn drain_tasks_to_wake(&mut self, tasks: &mut [u64]) -> usize {
-        let len = tasks.len();
-        let count = min(len, self.tasks_to_wake.len());
-        for (entry, task_id) in tasks.iter_mut().zip(self.tasks_to_wake.drain(..count)) {
-            *entry = task_id;
-        }
+    /// The CPU whose run queue the process should go on: where it last ran, or else this
+    /// CPU, if its affinity allows.
+    fn pick_cpu(&self) -> usize {
+        [self.last_cpu, arch::cpu_num()]
+            .into_iter()
+            .find(|&cpu| self.sched.allows(cpu))
+            .unwrap_or((self.sched.affinity & PerCpu::online()).trailing_zeros() as usize)
+    }
+
+    pub fn drain_tasks_to_wake(&mut self, tasks: &UserSlice<u64>) -> Result<usize, Error> {
+        let count = min(tasks.len(), self.tasks_to_wake.len());
+        let ids: Vec<u64> = self.tasks_to_wake.iter().take(count).copied().collect();
+        tasks.write(self.address_space(), &ids)?;
+        self.tasks_to_wake.drain(..count);
 
-        count
+        Ok(count)
     }
 }
 
 impl Drop for Process {
     fn drop(&mut self) {
-        if RUNNABLE.lock().iter().any(|&pid| pid == self.pid) {
-            panic!("dropping process that exists on the runnable queue");
+        if (0..PerCpu::count()).any(|cpu| PerCpu::run_queue(cpu).lock().contains(self.pid)) {
+            panic!("dropping process that exists on a run queue");
         }
-        arch::free_tree(self.vm_root);
         println!("[cpu:{} dropped pid:{}]", arch::cpu_num(), self.pid);
     }
 }
 
 static NEXT_PID: AtomicU64 = AtomicU64::new(1);
 pub static ALL: Mutex<BTreeMap<u64, Process>> = Mutex::new(BTreeMap::new());
-pub static RUNNABLE: Mutex<VecDeque<u64>> = Mutex::new(VecDeque::new());
 
-pub fn schedule_pid(pid: u64) {
-    let mut handle = RUNNABLE.lock();
-    // assert!(handle.iter().all(|&p| p != pid), "double scheduling {}!", pid);
-    if handle.iter().any(|&p| p == pid) {
+/// Put `proc` on a run queue, unless it's queued or on a CPU already, or has nothing to
+/// do. Another CPU is kicked with an IPI so it notices the work.
+fn enqueue(proc: &mut Process) {
+    if proc.queued || proc.on_cpu.is_some() || !proc.wants_cpu() {
         return;
     }
-    // println!("[cpu:{} scheduling pid:{}]", arch::cpu_num(), pid);
-    handle.push_back(pid);
+
+    let cpu = proc.pick_cpu();
+    proc.queued = true;
+    PerCpu::run_queue(cpu)
+        .lock()
+        .push(proc.pid, proc.sched.priority, proc.sched.affinity);
+    if cpu != arch::cpu_num() {
+        ipi::kick(cpu);
+    }
+}
+
+/// Queue `pid` to run. Takes `ALL`, so it must not be called with it held; use
+/// `schedule_wakeup` from inside `with`.
+pub fn schedule_pid(pid: u64) {
+    with(pid, enqueue);
+}
+
+/// Take `pid`, which was running on this CPU, off it. It goes back on a run queue if it
+/// still has work to do.
+pub fn deschedule(pid: u64) {
+    PerCpu::set_running(None);
+    with(pid, |p| {
+        p.on_cpu = None;
+        enqueue(p);
+    });
+}
+
+/// The next process for this CPU: from its own run queue, or stolen from another CPU's.
+/// Also returns whether it was picked to relieve starvation.
+fn next_runnable() -> Option<(u64, bool)> {
+    let cpu = arch::cpu_num();
+    if let Some(next) = PerCpu::run_queue(cpu).lock().pop() {
+        return Some(next);
+    }
+    let count = PerCpu::count();
+    (1..count)
+        .map(|offset| (cpu + offset) % count)
+        .filter(|&victim| PerCpu::is_online(victim))
+        .find_map(|victim| PerCpu::run_queue(victim).lock().steal(cpu))
+        .map(|pid| (pid, false))
+}
+
+/// What a CPU does when it has no process to run: poll kernel tasks, take any runnable
+/// process, and halt until an interrupt when there's nothing left to do.
+///
+/// This is entered from interrupt handlers too, whose stack is abandoned along with
+/// this loop's once a process runs.
+pub fn idle_loop() -> ! {
+    PerCpu::timer_mut().set_preempt_at(None);
+    loop {
+        arch::disable_interrupts();
+        PerCpu::executor_mut().do_work();
+        maybe_run_usermode_program(false);
+        // Wakers check `idle` after queueing work, and we check for work after setting
+        // it, so either we see the work or the waker kicks us out of the halt.
+        PerCpu::set_idle(true);
+        if !PerCpu::executor_for_cpu(arch::cpu_num()).has_work() {
+            arch::enable_interrupts_and_halt();
+        }
+        PerCpu::set_idle(false);
+    }
 }
 
+/// Switch to the next runnable process, if there is one.
+///
+/// With `swap_in_current` the process on this CPU goes back on a run queue and competes
+/// for the CPU too. This only returns if it's picked again, and goes idle if it was
+/// moved to another CPU and there's nothing else to run. Without `swap_in_current` this
+/// returns if there's nothing to run.
 pub fn maybe_run_usermode_program(swap_in_current: bool) {
-    let pid = {
-        let mut binding = RUNNABLE.lock();
-        let Some(pid) = binding.pop_front() else {
-            return;
-        };
-        if swap_in_current {
-            binding.push_back(PerCpu::running().unwrap())
+    let current = swap_in_current.then(|| PerCpu::running().unwrap());
+    if let Some(current) = current {
+        deschedule(current);
+    }
+
+    match next_runnable() {
+        Some((pid, relief)) if Some(pid) == current => {
+            PerCpu::set_running(Some(pid));
+            with(pid, |p| {
+                p.queued = false;
+                p.relief = relief;
+                p.on_cpu = Some(arch::cpu_num());
+                p.renew_quantum();
+            });
         }
-        pid
-    };
-    Process::run(pid)
+        Some((pid, relief)) => Process::run(pid, relief),
+        None if current.is_some() => idle_loop(),
+        None => {}
+    }
+}
+
+/// Make `pid`, which is running on this CPU, exit with `code` once its interrupt returns.
+pub fn kill(pid: u64, code: u64) {
+    with(pid, |p| p.exit(code));
+}
+
+/// Fill in the page at `addr` that `pid` faulted on, if it may access it.
+pub fn handle_page_fault(pid: u64, addr: usize, write: bool) -> Result<(), Fault> {
+    let space = with(pid, |p| p.address_space().clone()).expect("fault in a missing process");
+    space.fault(addr, write)
 }
 
 pub fn exit(code: u64) -> u64 {
@@ -198,20 +405,178 @@ pub fn exit(code: u64) -> u64 {
     code
 }
 
-pub fn spawn(_name: &str, arg: usize) -> u64 {
+/// Start the boot module `name` on behalf of `parent`, which is expected to hold the
+/// only handle to it. The child's `argv` is the module path followed by `args`, and it
+/// inherits the parent's environment.
+pub fn spawn(name: &str, args: &[String], parent: u64) -> Result<u64, Error> {
+    let module = modules::find(name).ok_or(Error::NotFound)?;
+    if !map::is_loadable_elf(module.data) {
+        return Err(Error::InvalidArgument);
+    }
+
+    let mut argv = vec![module.path.clone()];
+    argv.extend_from_slice(args);
+    let (env, params) = with(parent, |p| (p.env.clone(), p.sched)).unwrap_or_default();
+    stack::check_size(&argv, &env)?;
+
     unsafe {
-        let pid = Process::new(&*elf_data(), arg);
+        let pid = Process::new(module.data, &argv, env, params, true)?;
         schedule_pid(pid);
-        pid
+        Ok(pid)
     }
 }
 
+/// Start a copy of `parent`, which is in the syscall that `frame` entered with, on
+/// behalf of `parent` itself. The child shares the parent's memory copy-on-write and
+/// holds its handles, and returns from the syscall with 0 and no tasks to wake. Tasks
+/// the parent was waiting on are never woken in the child.
+pub fn fork(parent: u64, frame: &InterruptFrame) -> Result<u64, Error> {
+    let (space, env, params, handles) = with(parent, |p| {
+        (p.address_space().clone(), p.env.clone(), p.sched, p.handles.clone())
+    })
+    .ok_or(Error::NotFound)?;
+
+    let mut context = Context::new(frame);
+    context.frame.set_syscall_return(SyscallReturn::Complete(0));
+    context.frame.set_tasks_to_wake_count(0);
+
+    let space = Arc::new(space.fork()?);
+    let child = Process::from_parts(context, space, env, params, handles, true);
+    let pid = child.pid;
+    ALL.lock().insert(pid, child);
+    schedule_pid(pid);
+    Ok(pid)
+}
+
+/// Start a boot module that nothing holds a handle to, taking its arguments from the
+/// module command line.
+pub fn spawn_boot_module(module: &modules::BootModule) -> u64 {
+    let argv = module.argv();
+    let env = Vec::new();
+    stack::check_size(&argv, &env).expect("boot module command line too long");
+
+    unsafe { Process::new(module.data, &argv, env, sched::Params::default(), false) }
+        .expect("no memory to start boot module")
+}
+
 pub fn with<T, F: FnMut(&mut Process) -> T>(pid: u64, func: F) -> Option<T> {
     ALL.lock().get_mut(&pid).map(func)
 }
 
 pub fn remove(pid: u64) {
-    ALL.lock().remove(&pid);
+    // Dropping a process can drop handles, which take `ALL`.
+    let process = ALL.lock().remove(&pid);
+    drop(process);
+}
+
+/// Move `pid` to the scheduling class `priority` on behalf of `caller`, which may not
+/// put it in a class more urgent than its own.
+pub fn set_priority(caller: u64, pid: u64, priority: Priority) -> Result<(), Error> {
+    let allowed = with(caller, |p| priority as u64 >= p.sched.priority as u64);
+    if allowed != Some(true) {
+        return Err(Error::PermissionDenied);
+    }
+    with(pid, |p| {
+        p.sched.priority = priority;
+        requeue(p);
+    })
+    .ok_or(Error::NotFound)
+}
+
+/// Restrict `pid` to the CPUs in the bitmask `affinity`. If it's running elsewhere it
+/// moves at its next interrupt.
+pub fn set_affinity(pid: u64, affinity: u64) -> Result<(), Error> {
+    if !sched::valid_affinity(affinity) {
+        return Err(Error::InvalidArgument);
+    }
+    with(pid, |p| {
+        p.sched.affinity = affinity;
+        requeue(p);
+    })
+    .ok_or(Error::NotFound)
+}
+
+/// Queue `proc` again after its scheduling parameters changed. If it isn't on any run
+/// queue it's either not queued at all or about to run, and nothing needs to move.
+fn requeue(proc: &mut Process) {
+    let removed = (0..PerCpu::count()).any(|cpu| PerCpu::run_queue(cpu).lock().remove(proc.pid));
+    if removed {
+        proc.queued = false;
+        enqueue(proc);
+    }
+}
+
+/// Clean up after a process that has exited and is no longer on any CPU.
+///
+/// The address space is freed and the process's handles are closed right away. If some
+/// process holds a handle to it, it stays in `ALL` as a zombie until that handle is
+/// closed, otherwise it's removed.
+pub fn retire(pid: u64) {
+    let (removed, space, handles, waiters) = {
+        let mut all = ALL.lock();
+        let Some(proc) = all.get_mut(&pid) else {
+            return;
+        };
+
+        let space = proc.space.take();
+        proc.state = ProcessState::Zombie;
+        let handles = proc.handles.take_all();
+        let waiters = core::mem::take(&mut proc.exit_waiters);
+
+        let removed = if proc.has_handle {
+            None
+        } else {
+            all.remove(&pid)
+        };
+        (removed, space, handles, waiters)
+    };
+
+    // Dropping a process prints, and dropping handles closes objects which can take `ALL`
+    // again, so do both outside of the lock.
+    drop(space);
+    drop(removed);
+    drop(handles);
+
+    for (waiter, task_id) in waiters {
+        schedule_wakeup(waiter, task_id);
+    }
+}
+
+/// The last handle to `pid` was closed. A zombie is removed, and a running process will
+/// be removed as soon as it exits.
+pub fn release(pid: u64) {
+    let removed = {
+        let mut all = ALL.lock();
+        let Some(proc) = all.get_mut(&pid) else {
+            return;
+        };
+        proc.has_handle = false;
+        if proc.state == ProcessState::Zombie {
+            all.remove(&pid)
+        } else {
+            None
+        }
+    };
+    drop(removed);
+}
+
+/// Collect the exit code of `child` for `task_id` in `waiter`, which holds a handle to it.
+///
+/// If the child hasn't exited yet, the task is woken when it does and the syscall is
+/// `NotComplete`.
+pub fn wait_for(waiter: u64, child: u64, task_id: u64) -> Result<SyscallReturn, Error> {
+    let mut all = ALL.lock();
+    let proc = all.get_mut(&child).ok_or(Error::NotFound)?;
+
+    if proc.state == ProcessState::Zombie {
+        let code = proc.exit_code.expect("zombie process without an exit code");
+        return Ok(SyscallReturn::Complete(code));
+    }
+
+    if !proc.exit_waiters.contains(&(waiter, task_id)) {
+        proc.exit_waiters.push((waiter, task_id));
+    }
+    Ok(SyscallReturn::NotComplete)
 }
 
 pub fn backtrace_local() {
@@ -228,7 +593,10 @@ pub fn backtrace_all() {
 
 pub fn schedule_wakeup(pid: u64, task_id: u64) {
     with(pid, |proc| {
+        if matches!(proc.state, ProcessState::Exited | ProcessState::Zombie) {
+            return;
+        }
         proc.tasks_to_wake.push_back(task_id);
-        schedule_pid(pid);
+        enqueue(proc);
     });
 }
\ No newline at end of file
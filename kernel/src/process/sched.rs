@@ -0,0 +1,246 @@
+//! Scheduling policy.
+//!
+//! Each CPU has a `RunQueue` in its `PerCpu`, which keeps a FIFO per `Priority` class.
+//! A process that becomes runnable goes on the queue of the CPU it last ran on, if its
+//! affinity allows, and that CPU is kicked with an IPI. A CPU with nothing to run takes
+//! the next process from the most urgent class on its own queue, except that a class
+//! passed over `STARVATION_LIMIT` times in a row goes first. If its own queue is empty
+//! it steals from another CPU's.
+//!
+//! A process runs until it waits, exits or uses up its quantum, or until a process in a
+//! more urgent class is queued on its CPU. One picked to relieve starvation keeps the CPU
+//! for its whole quantum regardless. If something else is runnable by then it goes
+//! to the back of its class's queue, so processes in the same class take turns.
+
+use super::{ProcessState, ALL};
+use crate::cmdline;
+use crate::per_cpu::PerCpu;
+use crate::print::println;
+use alloc::collections::VecDeque;
+use cardinal3_interface::Priority;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Time slice length in timer ticks, unless the command line sets `quantum=`.
+pub const DEFAULT_QUANTUM: u64 = 10;
+
+static QUANTUM: AtomicU64 = AtomicU64::new(DEFAULT_QUANTUM);
+
+pub fn init() {
+    if let Some(ticks) = cmdline::parse("quantum") {
+        set_quantum(ticks);
+    }
+    println!("scheduler quantum: {} ticks", quantum());
+}
+
+pub fn quantum() -> u64 {
+    QUANTUM.load(Ordering::Relaxed)
+}
+
+pub fn set_quantum(ticks: u64) {
+    QUANTUM.store(ticks.max(1), Ordering::Relaxed);
+}
+
+/// Affinity that allows every CPU.
+fn all_cpus() -> u64 {
+    match PerCpu::count() {
+        64.. => u64::MAX,
+        count => (1 << count) - 1,
+    }
+}
+
+/// Whether `affinity` is a CPU bitmask a process can be restricted to: it must name at
+/// least one online CPU.
+pub fn valid_affinity(affinity: u64) -> bool {
+    affinity & !all_cpus() == 0 && affinity & PerCpu::online() != 0
+}
+
+/// How a process is scheduled. Children inherit these from the process that spawned
+/// them.
+#[derive(Copy, Clone, Debug)]
+pub struct Params {
+    pub priority: Priority,
+    /// Bitmask of the CPUs the process may run on.
+    pub affinity: u64,
+}
+
+impl Params {
+    pub fn allows(&self, cpu: usize) -> bool {
+        self.affinity & 1 << cpu != 0
+    }
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            priority: Priority::Normal,
+            affinity: all_cpus(),
+        }
+    }
+}
+
+const CLASSES: usize = 3;
+
+/// How many times in a row a class with runnable processes can be passed over for more
+/// urgent ones before it gets a turn anyway.
+const STARVATION_LIMIT: u32 = 4;
+
+/// Runnable processes waiting for one CPU. Entries carry the process's affinity so
+/// other CPUs can steal from the queue without looking the process up.
+pub struct RunQueue {
+    classes: [VecDeque<(u64, u64)>; CLASSES],
+    passed_over: [u32; CLASSES],
+}
+
+impl RunQueue {
+    pub const fn new() -> Self {
+        Self {
+            classes: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            passed_over: [0; CLASSES],
+        }
+    }
+
+    pub fn contains(&self, pid: u64) -> bool {
+        self.classes.iter().flatten().any(|&(p, _)| p == pid)
+    }
+
+    /// Queue `pid` at the back of its class.
+    pub fn push(&mut self, pid: u64, priority: Priority, affinity: u64) {
+        self.classes[priority as usize].push_back((pid, affinity));
+    }
+
+    /// Take the next process to run, and whether it was picked to relieve starvation.
+    pub fn pop(&mut self) -> Option<(u64, bool)> {
+        let runnable = |class: &usize| !self.classes[*class].is_empty();
+        let class = (0..CLASSES)
+            .rev()
+            .filter(runnable)
+            .find(|&class| self.passed_over[class] >= STARVATION_LIMIT)
+            .or_else(|| (0..CLASSES).find(runnable))?;
+
+        let relief = (0..class).any(|urgent| !self.classes[urgent].is_empty());
+        for other in class + 1..CLASSES {
+            if !self.classes[other].is_empty() {
+                self.passed_over[other] += 1;
+            }
+        }
+        self.passed_over[class] = 0;
+        self.classes[class].pop_front().map(|(pid, _)| (pid, relief))
+    }
+
+    /// Take the most urgent process that's allowed to run on `cpu`, for a CPU with an
+    /// empty queue.
+    pub fn steal(&mut self, cpu: usize) -> Option<u64> {
+        self.classes.iter_mut().find_map(|class| {
+            let index = class.iter().position(|&(_, affinity)| affinity & 1 << cpu != 0)?;
+            class.remove(index).map(|(pid, _)| pid)
+        })
+    }
+
+    /// Remove `pid`, returning whether it was queued here.
+    pub fn remove(&mut self, pid: u64) -> bool {
+        for class in &mut self.classes {
+            if let Some(index) = class.iter().position(|&(p, _)| p == pid) {
+                class.remove(index);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Whether a process more urgent than `priority` is waiting to run.
+    pub fn has_more_urgent(&self, priority: Priority) -> bool {
+        self.classes[..priority as usize].iter().any(|class| !class.is_empty())
+    }
+}
+
+/// Print how much CPU time each process has used and how often it was switched in.
+pub fn dump_stats() {
+    let all = ALL.lock();
+    println!("  pid state    class    cpu affinity       ticks   switches");
+    for proc in all.values() {
+        let cpu = match proc.on_cpu {
+            Some(cpu) => cpu as i64,
+            None => -1,
+        };
+        println!(
+            "{:>5} {:<8} {:<8} {:>3} {:>#8x} {:>10} {:>10}",
+            proc.pid,
+            state_name(proc.state),
+            class_name(proc.sched.priority),
+            cpu,
+            proc.sched.affinity,
+            proc.cpu_ticks,
+            proc.switches,
+        );
+    }
+}
+
+fn state_name(state: ProcessState) -> &'static str {
+    match state {
+        ProcessState::Running => "running",
+        ProcessState::Waiting => "waiting",
+        ProcessState::Exited => "exited",
+        ProcessState::Zombie => "zombie",
+    }
+}
+
+fn class_name(priority: Priority) -> &'static str {
+    match priority {
+        Priority::Realtime => "realtime",
+        Priority::Normal => "normal",
+        Priority::Idle => "idle",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pops_most_urgent_class_first_in_order() {
+        let mut queue = RunQueue::new();
+        queue.push(1, Priority::Idle, 1);
+        queue.push(2, Priority::Normal, 1);
+        queue.push(3, Priority::Realtime, 1);
+        queue.push(4, Priority::Normal, 1);
+
+        assert_eq!(queue.pop(), Some((3, false)));
+        assert_eq!(queue.pop(), Some((2, false)));
+        assert_eq!(queue.pop(), Some((4, false)));
+        assert_eq!(queue.pop(), Some((1, false)));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn relieves_a_starved_class() {
+        let mut queue = RunQueue::new();
+        queue.push(1, Priority::Normal, 1);
+        queue.push(2, Priority::Realtime, 1);
+
+        // The realtime process keeps going back on the queue, passing the normal one over
+        for _ in 0..STARVATION_LIMIT {
+            assert_eq!(queue.pop(), Some((2, false)));
+            queue.push(2, Priority::Realtime, 1);
+        }
+        assert_eq!(queue.pop(), Some((1, true)));
+        // Relief resets the count
+        queue.push(1, Priority::Normal, 1);
+        assert_eq!(queue.pop(), Some((2, false)));
+    }
+
+    #[test]
+    fn steal_respects_affinity() {
+        let mut queue = RunQueue::new();
+        queue.push(1, Priority::Realtime, 0b01);
+        queue.push(2, Priority::Normal, 0b01);
+        queue.push(3, Priority::Normal, 0b11);
+        queue.push(4, Priority::Idle, 0b10);
+
+        assert_eq!(queue.steal(1), Some(3));
+        assert_eq!(queue.steal(1), Some(4));
+        assert_eq!(queue.steal(1), None);
+        assert_eq!(queue.steal(2), None);
+        assert!(queue.contains(1) && queue.contains(2));
+        assert_eq!(queue.steal(0), Some(1));
+    }
+}
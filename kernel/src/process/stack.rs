@@ -0,0 +1,81 @@
+use crate::arch;
+use crate::process::AddressSpace;
+use crate::user_mem;
+use alloc::string::String;
+use alloc::vec::Vec;
+use cardinal3_interface::{AuxType, Error};
+use core::mem::size_of;
+
+/// How much of the user stack the initial arguments and environment may take up.
+const MAX_ARGS_SIZE: usize = arch::USER_STACK_PAGES * arch::PAGE_SIZE / 4;
+
+/// Room reserved for auxiliary vector entries when checking sizes up front.
+const MAX_AUXV: usize = 8;
+
+fn strings_size(argv: &[String], env: &[String]) -> usize {
+    argv.iter().chain(env).map(|s| s.len() + 1).sum()
+}
+
+fn words_len(argv: &[String], env: &[String], auxc: usize) -> usize {
+    // return address, argc, argv + null, envp + null, auxv + null pair
+    2 + argv.len() + 1 + env.len() + 1 + (auxc + 1) * 2
+}
+
+/// Check that `argv` and `env` will fit on a new process's stack.
+pub fn check_size(argv: &[String], env: &[String]) -> Result<(), Error> {
+    let words = words_len(argv, env, MAX_AUXV);
+    if strings_size(argv, env) + words * size_of::<u64>() > MAX_ARGS_SIZE {
+        return Err(Error::InvalidArgument);
+    }
+    Ok(())
+}
+
+/// Write the initial stack described in `cardinal3_interface` below `USER_STACK_TOP`.
+///
+/// Returns the initial stack pointer and the address of `argc`, or `Error::OutOfMemory`
+/// if there was no memory for the stack pages.
+pub fn build_initial_stack(
+    space: &AddressSpace,
+    argv: &[String],
+    env: &[String],
+    auxv: &[(AuxType, u64)],
+) -> Result<(usize, usize), Error> {
+    check_size(argv, env).expect("initial stack too large");
+    assert!(auxv.len() <= MAX_AUXV);
+
+    let mut strings = Vec::with_capacity(strings_size(argv, env));
+    let mut offsets = Vec::with_capacity(argv.len() + env.len());
+    for s in argv.iter().chain(env) {
+        offsets.push(strings.len());
+        strings.extend_from_slice(s.as_bytes());
+        strings.push(0);
+    }
+    let strings_base = (arch::USER_STACK_TOP - strings.len()) & !0xf;
+    let (argv_offsets, env_offsets) = offsets.split_at(argv.len());
+
+    let mut words = Vec::with_capacity(words_len(argv, env, auxv.len()));
+    words.push(0);
+    words.push(argv.len() as u64);
+    words.extend(argv_offsets.iter().map(|&o| (strings_base + o) as u64));
+    words.push(0);
+    words.extend(env_offsets.iter().map(|&o| (strings_base + o) as u64));
+    words.push(0);
+    for &(typ, value) in auxv {
+        words.push(typ as u64);
+        words.push(value);
+    }
+    words.push(AuxType::Null as u64);
+    words.push(0);
+
+    // argc goes on a 16 byte boundary, with the return address just below it.
+    let argc_addr = (strings_base - (words.len() - 1) * size_of::<u64>()) & !0xf;
+    let sp = argc_addr - size_of::<u64>();
+
+    let words_bytes = unsafe {
+        core::slice::from_raw_parts(words.as_ptr() as *const u8, words.len() * size_of::<u64>())
+    };
+    user_mem::copy_to_user(space, strings_base, &strings)?;
+    user_mem::copy_to_user(space, sp, words_bytes)?;
+
+    Ok((sp, argc_addr))
+}
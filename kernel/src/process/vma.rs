@@ -0,0 +1,403 @@
+//! Address spaces and the regions (VMAs) they're made of.
+//!
+//! Nothing is mapped up front. A region only records what its pages should hold, and
+//! each page is filled in the first time it's touched: by the process itself through a
+//! page fault, or by the kernel through `user_mem`. A fault outside any region, or one
+//! the region doesn't allow, is the process's fault.
+//!
+//! A forked address space shares every page that's been filled in with its parent. Pages
+//! either side may write to are mapped copy-on-write in both, and whoever writes first
+//! takes its own copy, or just the page back if nobody else holds it any more.
+
+use crate::arch::{self, PageTable, PAGE_MASK, PAGE_SIZE};
+use crate::pmm;
+use crate::vmm::PageFlags;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use cardinal3_interface::{Error, MapFlags};
+use core::ops::Range;
+use spin::Mutex;
+
+/// What the pages of a region hold before they're first written.
+#[derive(Debug, Copy, Clone)]
+pub enum Backing {
+    /// Zeros. Made by the `Map` syscall.
+    Anonymous,
+    /// Zeros, for the stack.
+    Stack,
+    /// A segment of a program: `data` is its contents from the file, starting at `vaddr`,
+    /// and the rest of it is zeros.
+    File { vaddr: usize, data: &'static [u8] },
+}
+
+/// A run of pages mapped with the same flags.
+#[derive(Debug, Copy, Clone)]
+struct Vma {
+    end: usize,
+    flags: PageFlags,
+    backing: Backing,
+}
+
+/// Why a page couldn't be filled in.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Fault {
+    /// The address isn't in any region.
+    Unmapped,
+    /// The address is in the guard page below the stack.
+    StackOverflow,
+    /// The region doesn't allow the access.
+    Protection,
+    OutOfMemory,
+}
+
+impl From<Fault> for Error {
+    fn from(fault: Fault) -> Self {
+        match fault {
+            Fault::OutOfMemory => Error::OutOfMemory,
+            _ => Error::BadAddress,
+        }
+    }
+}
+
+/// A process's page tables and the regions that may be mapped in them. The tables are
+/// freed when the last reference goes.
+pub struct AddressSpace {
+    root: *mut PageTable,
+    vmas: Mutex<VmaList>,
+}
+
+// Rust is mad because of the PageTable, but it's only changed with `vmas` locked.
+unsafe impl Send for AddressSpace {}
+unsafe impl Sync for AddressSpace {}
+
+impl AddressSpace {
+    pub fn new() -> Result<Self, Error> {
+        Ok(Self {
+            root: arch::new_tree().ok_or(Error::OutOfMemory)?,
+            vmas: Mutex::new(VmaList::new()),
+        })
+    }
+
+    pub fn root(&self) -> *mut PageTable {
+        self.root
+    }
+
+    /// Add a region over `range`, replacing whatever was there.
+    pub fn add(&self, range: Range<usize>, flags: PageFlags, backing: Backing) {
+        self.vmas.lock().add(self.root, range, flags, backing);
+    }
+
+    /// Reserve `len` bytes of zeroed memory and return its address.
+    pub fn map(&self, len: u64, flags: MapFlags) -> Result<usize, Error> {
+        self.vmas.lock().map(len, flags)
+    }
+
+    pub fn unmap(&self, addr: u64, len: u64) -> Result<(), Error> {
+        self.vmas.lock().unmap(self.root, addr, len)
+    }
+
+    pub fn protect(&self, addr: u64, len: u64, flags: MapFlags) -> Result<(), Error> {
+        self.vmas.lock().protect(self.root, addr, len, flags)
+    }
+
+    /// A copy of this address space for a forked process, sharing every page that's been
+    /// filled in. Fails with `Error::OutOfMemory` if there's no memory for its page tables.
+    pub fn fork(&self) -> Result<Self, Error> {
+        let vmas = self.vmas.lock();
+        let child = Self::new()?;
+        for (&start, vma) in &vmas.regions {
+            // Read-only program text can never be written, so it's simply shared
+            let copy_on_write = vma.flags.contains(PageFlags::WRITE)
+                || !matches!(vma.backing, Backing::File { .. });
+            for page in (start..vma.end).step_by(PAGE_SIZE) {
+                if !unsafe { arch::share_in_table(self.root, child.root, page, copy_on_write) } {
+                    // Dropping the child gives back the pages it shares so far
+                    return Err(Error::OutOfMemory);
+                }
+            }
+        }
+        child.vmas.lock().regions = vmas.regions.clone();
+        Ok(child)
+    }
+
+    /// Fill in the page holding `addr` for an access that faulted.
+    pub fn fault(&self, addr: usize, write: bool) -> Result<(), Fault> {
+        self.vmas.lock().fault(self.root, addr, write)
+    }
+
+    /// The physical address of `addr` if usermode may access it (and write to it, if
+    /// `write` is set), filling in its page first if it hasn't been touched yet.
+    pub fn physical_address(&self, addr: usize, write: bool) -> Result<u64, Error> {
+        if let Some(phys) = arch::user_physical_address(self.root, addr, write) {
+            return Ok(phys);
+        }
+        self.fault(addr, write)?;
+        arch::user_physical_address(self.root, addr, write).ok_or(Error::BadAddress)
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        arch::free_tree(self.root);
+    }
+}
+
+/// A process's regions by start address. They never overlap.
+struct VmaList {
+    regions: BTreeMap<usize, Vma>,
+}
+
+fn page_flags(flags: MapFlags) -> Result<PageFlags, Error> {
+    if !flags.contains(MapFlags::READ) {
+        return Err(Error::InvalidArgument);
+    }
+    let mut page_flags = PageFlags::READ | PageFlags::USER;
+    if flags.contains(MapFlags::WRITE) {
+        page_flags |= PageFlags::WRITE;
+    }
+    if flags.contains(MapFlags::EXECUTE) {
+        page_flags |= PageFlags::EXECUTE;
+    }
+    Ok(page_flags)
+}
+
+/// The whole pages covered by `len` bytes at `addr`, which must be page aligned and lie
+/// in the map window.
+fn page_range(addr: u64, len: u64) -> Result<Range<usize>, Error> {
+    let addr = addr as usize;
+    let len = (len as usize)
+        .checked_next_multiple_of(PAGE_SIZE)
+        .ok_or(Error::InvalidArgument)?;
+    if !addr.is_multiple_of(PAGE_SIZE) || len == 0 {
+        return Err(Error::InvalidArgument);
+    }
+    let end = addr.checked_add(len).ok_or(Error::BadAddress)?;
+    if addr < arch::USER_MAP_BASE || end > arch::USER_MAP_END {
+        return Err(Error::BadAddress);
+    }
+    Ok(addr..end)
+}
+
+fn unmap_pages(root: *mut PageTable, range: Range<usize>) {
+    for page in range.step_by(PAGE_SIZE) {
+        if let Some(phys) = unsafe { arch::unmap_in_table(root, page) } {
+            pmm::free(phys);
+        }
+    }
+}
+
+/// A new page holding what the page at `page` in a region with `backing` starts out as.
+fn fill_page(page: usize, backing: Backing) -> Result<u64, Fault> {
+    let phys = pmm::alloc(pmm::Owner::User).ok_or(Fault::OutOfMemory)?;
+    let mapped = arch::direct_map_offset(phys) as *mut u8;
+    unsafe { core::ptr::write_bytes(mapped, 0, PAGE_SIZE) };
+
+    if let Backing::File { vaddr, data } = backing {
+        let start = page.max(vaddr);
+        let end = (page + PAGE_SIZE).min(vaddr + data.len());
+        if start < end {
+            let src = &data[start - vaddr..end - vaddr];
+            unsafe { mapped.add(start - page).copy_from_nonoverlapping(src.as_ptr(), src.len()) };
+        }
+    }
+    Ok(phys)
+}
+
+/// Give the copy-on-write page `phys` at `page` back to the process to write to, copying
+/// it first if it's still mapped anywhere else.
+fn copy_page(root: *mut PageTable, page: usize, phys: u64, flags: PageFlags) -> Result<(), Fault> {
+    if !pmm::is_shared(phys) {
+        // The tables are already there, so this can't run out of memory
+        unsafe { arch::map_in_table(root, page, phys, flags) };
+        return Ok(());
+    }
+
+    let copy = pmm::alloc(pmm::Owner::User).ok_or(Fault::OutOfMemory)?;
+    let mapped = unsafe {
+        let src = arch::direct_map_offset(phys) as *const u8;
+        let dst = arch::direct_map_offset(copy) as *mut u8;
+        dst.copy_from_nonoverlapping(src, PAGE_SIZE);
+        arch::map_in_table(root, page, copy, flags)
+    };
+    if !mapped {
+        pmm::free(copy);
+        return Err(Fault::OutOfMemory);
+    }
+    pmm::free(phys);
+    Ok(())
+}
+
+/// The page of the program file itself that a read-only segment can share at `page`, if
+/// the file has a whole page of the segment there.
+fn file_page(page: usize, vaddr: usize, data: &'static [u8]) -> Option<u64> {
+    let file_base = (data.as_ptr() as usize).wrapping_sub(vaddr);
+    if file_base & PAGE_MASK != 0 || page + PAGE_SIZE > vaddr + data.len() {
+        return None;
+    }
+    arch::physical_address(file_base.wrapping_add(page))
+}
+
+impl VmaList {
+    fn new() -> Self {
+        Self {
+            regions: BTreeMap::new(),
+        }
+    }
+
+    /// The region holding `addr`.
+    fn find(&self, addr: usize) -> Option<&Vma> {
+        let (_, vma) = self.regions.range(..=addr).next_back()?;
+        (addr < vma.end).then_some(vma)
+    }
+
+    /// The lowest address in the map window with `len` bytes free after it.
+    fn find_gap(&self, len: usize) -> Option<usize> {
+        let mut start = arch::USER_MAP_BASE;
+        for (&base, vma) in self.regions.range(arch::USER_MAP_BASE..arch::USER_MAP_END) {
+            if base - start >= len {
+                return Some(start);
+            }
+            start = vma.end;
+        }
+        (arch::USER_MAP_END - start >= len).then_some(start)
+    }
+
+    /// Make sure no region straddles `addr`.
+    fn split(&mut self, addr: usize) {
+        let Some((_, vma)) = self.regions.range_mut(..addr).next_back() else {
+            return;
+        };
+        if vma.end > addr {
+            let upper = *vma;
+            vma.end = addr;
+            self.regions.insert(addr, upper);
+        }
+    }
+
+    /// Remove the regions in `range` and free their pages.
+    fn remove(&mut self, root: *mut PageTable, range: Range<usize>) {
+        self.split(range.start);
+        self.split(range.end);
+
+        let starts: Vec<usize> = self.regions.range(range).map(|(&start, _)| start).collect();
+        for start in starts {
+            let vma = self.regions.remove(&start).unwrap();
+            unmap_pages(root, start..vma.end);
+        }
+    }
+
+    fn add(
+        &mut self,
+        root: *mut PageTable,
+        range: Range<usize>,
+        flags: PageFlags,
+        backing: Backing,
+    ) {
+        self.remove(root, range.clone());
+        let end = range.end;
+        self.regions.insert(range.start, Vma { end, flags, backing });
+    }
+
+    fn map(&mut self, len: u64, flags: MapFlags) -> Result<usize, Error> {
+        let flags = page_flags(flags)?;
+        let len = (len as usize)
+            .checked_next_multiple_of(PAGE_SIZE)
+            .filter(|&len| len > 0)
+            .ok_or(Error::InvalidArgument)?;
+        // Pages are only filled in as they're touched, but never promise more than is free
+        if len / PAGE_SIZE > pmm::free_pages() {
+            return Err(Error::OutOfMemory);
+        }
+        let start = self.find_gap(len).ok_or(Error::OutOfMemory)?;
+        let backing = Backing::Anonymous;
+        self.regions.insert(start, Vma { end: start + len, flags, backing });
+        Ok(start)
+    }
+
+    /// Unmap and free every page of a region in `len` bytes at `addr`.
+    fn unmap(&mut self, root: *mut PageTable, addr: u64, len: u64) -> Result<(), Error> {
+        let range = page_range(addr, len)?;
+        self.remove(root, range);
+        Ok(())
+    }
+
+    /// Change the access allowed to `len` bytes at `addr`, which must all be in regions
+    /// made by `Map`.
+    fn protect(
+        &mut self,
+        root: *mut PageTable,
+        addr: u64,
+        len: u64,
+        flags: MapFlags,
+    ) -> Result<(), Error> {
+        let flags = page_flags(flags)?;
+        let range = page_range(addr, len)?;
+        if !self.covers(range.clone()) {
+            return Err(Error::BadAddress);
+        }
+        self.split(range.start);
+        self.split(range.end);
+
+        for (&start, vma) in self.regions.range_mut(range) {
+            vma.flags = flags;
+            for page in (start..vma.end).step_by(PAGE_SIZE) {
+                unsafe { arch::protect_in_table(root, page, flags) };
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether every page in `range` is in a region made by `Map`.
+    fn covers(&self, range: Range<usize>) -> bool {
+        let mut next = range.start;
+        let first = self.regions.range(..=range.start).next_back();
+        let rest = self.regions.range(range.start + 1..range.end);
+        for (&start, vma) in first.into_iter().chain(rest) {
+            if start > next || !matches!(vma.backing, Backing::Anonymous) {
+                return false;
+            }
+            next = next.max(vma.end);
+        }
+        next >= range.end
+    }
+
+    fn fault(&mut self, root: *mut PageTable, addr: usize, write: bool) -> Result<(), Fault> {
+        let Some(&vma) = self.find(addr) else {
+            let guard = arch::USER_STACK_BASE - PAGE_SIZE..arch::USER_STACK_BASE;
+            return Err(if guard.contains(&addr) {
+                Fault::StackOverflow
+            } else {
+                Fault::Unmapped
+            });
+        };
+        if write && !vma.flags.contains(PageFlags::WRITE) {
+            return Err(Fault::Protection);
+        }
+
+        let page = addr & !PAGE_MASK;
+        if arch::user_physical_address(root, page, false).is_some() {
+            // The page is there, so either it's shared until written or the access
+            // wasn't allowed
+            return match unsafe { arch::copy_on_write_page(root, page) } {
+                Some(phys) if write => copy_page(root, page, phys, vma.flags),
+                _ => Err(Fault::Protection),
+            };
+        }
+
+        let shared = match vma.backing {
+            Backing::File { vaddr, data } if !vma.flags.contains(PageFlags::WRITE) => {
+                file_page(page, vaddr, data)
+            }
+            _ => None,
+        };
+        let phys = match shared {
+            Some(phys) => phys,
+            None => fill_page(page, vma.backing)?,
+        };
+        if !unsafe { arch::map_in_table(root, page, phys, vma.flags) } {
+            pmm::free(phys);
+            return Err(Fault::OutOfMemory);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,19 @@
+//! Async synchronization primitives for kernel tasks.
+//!
+//! Unlike `spin::Mutex`, these park the waiting task with its executor instead of
+//! spinning, and can be held across `.await`. They're all built on `WaitQueue`.
+//!
+//! Like the executor's wakers, they must be used with interrupts disabled, which is how
+//! kernel tasks and interrupt handlers run.
+
+pub mod mpsc;
+mod mutex;
+mod notify;
+pub mod oneshot;
+mod semaphore;
+mod wait_queue;
+
+pub use mutex::{Mutex, MutexGuard};
+pub use notify::{Notified, Notify};
+pub use semaphore::{Semaphore, SemaphorePermit};
+pub use wait_queue::{Wait, WaitQueue};
@@ -0,0 +1,150 @@
+//! A bounded channel with any number of senders and one receiver.
+
+use crate::sync::WaitQueue;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+
+/// The error from sending when the receiver is gone, with the value that wasn't sent.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Closed(T),
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    senders: usize,
+    receiver_alive: bool,
+}
+
+struct Shared<T> {
+    state: spin::Mutex<State<T>>,
+    capacity: usize,
+    /// The receiver, waiting for a value.
+    readers: WaitQueue,
+    /// Senders waiting for room.
+    writers: WaitQueue,
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Make a channel that holds up to `capacity` values before senders have to wait.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "mpsc channel needs room for at least one value");
+    let shared = Arc::new(Shared {
+        state: spin::Mutex::new(State {
+            queue: VecDeque::new(),
+            senders: 1,
+            receiver_alive: true,
+        }),
+        capacity,
+        readers: WaitQueue::new(),
+        writers: WaitQueue::new(),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+impl<T> Sender<T> {
+    /// Send `value`, waiting for room if the channel is full.
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let mut value = Some(value);
+        self.shared
+            .writers
+            .wait_until(|| match self.try_send(value.take().unwrap()) {
+                Ok(()) => Some(Ok(())),
+                Err(TrySendError::Closed(v)) => Some(Err(SendError(v))),
+                Err(TrySendError::Full(v)) => {
+                    value = Some(v);
+                    None
+                }
+            })
+            .await
+    }
+
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        {
+            let mut state = self.shared.state.lock();
+            if !state.receiver_alive {
+                return Err(TrySendError::Closed(value));
+            }
+            if state.queue.len() >= self.shared.capacity {
+                return Err(TrySendError::Full(value));
+            }
+            state.queue.push_back(value);
+        }
+        self.shared.readers.wake_one();
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let last = {
+            let mut state = self.shared.state.lock();
+            state.senders -= 1;
+            state.senders == 0
+        };
+        if last {
+            self.shared.readers.wake_all();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Wait for the next value. Returns `None` once every sender is gone and the
+    /// channel is empty.
+    pub async fn recv(&mut self) -> Option<T> {
+        self.shared
+            .readers
+            .wait_until(|| {
+                let mut state = self.shared.state.lock();
+                match state.queue.pop_front() {
+                    Some(value) => Some(Some(value)),
+                    None if state.senders == 0 => Some(None),
+                    None => None,
+                }
+            })
+            .await
+            .inspect(|_| {
+                self.shared.writers.wake_one();
+            })
+    }
+
+    pub fn try_recv(&mut self) -> Option<T> {
+        let value = self.shared.state.lock().queue.pop_front();
+        if value.is_some() {
+            self.shared.writers.wake_one();
+        }
+        value
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().receiver_alive = false;
+        self.shared.writers.wake_all();
+    }
+}
@@ -0,0 +1,70 @@
+use crate::sync::{Wait, WaitQueue};
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+/// Wakes tasks waiting for an event.
+///
+/// `notify_one` wakes one waiting task, or leaves a permit if nobody is waiting, so the
+/// next `notified` completes straight away and a notification sent just before waiting
+/// isn't lost. `notify_all` only wakes the tasks already waiting.
+pub struct Notify {
+    /// Held while `notify_one` looks for a waiter, so a `notified` can't check the permit
+    /// and join the queue in between.
+    permit: spin::Mutex<bool>,
+    waiters: WaitQueue,
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Self {
+            permit: spin::Mutex::new(false),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Wait for a notification. The permit is taken, or the task starts waiting, when
+    /// this is called rather than when it's first polled, so notifications sent in
+    /// between count.
+    pub fn notified(&self) -> Notified<'_> {
+        let mut permit = self.permit.lock();
+        let wait = (!core::mem::take(&mut *permit)).then(|| self.waiters.wait());
+        Notified { wait }
+    }
+
+    pub fn notify_one(&self) {
+        let mut permit = self.permit.lock();
+        // A woken waiter takes the notification with it
+        if !self.waiters.wake_one() {
+            *permit = true;
+        }
+    }
+
+    pub fn notify_all(&self) {
+        self.waiters.wake_all();
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Completes once notified. See `Notify::notified`.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Notified<'a> {
+    /// `None` if the permit was taken.
+    wait: Option<Wait<'a>>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match &mut self.wait {
+            Some(wait) => Pin::new(wait).poll(cx),
+            None => Poll::Ready(()),
+        }
+    }
+}
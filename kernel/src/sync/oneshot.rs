@@ -0,0 +1,93 @@
+//! A channel for sending a single value between tasks.
+
+use crate::sync::WaitQueue;
+use alloc::sync::Arc;
+
+/// The error from `Receiver::recv` when the sender was dropped without sending.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RecvError;
+
+struct State<T> {
+    value: Option<T>,
+    sender_alive: bool,
+    receiver_alive: bool,
+}
+
+struct Shared<T> {
+    state: spin::Mutex<State<T>>,
+    waiters: WaitQueue,
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: spin::Mutex::new(State {
+            value: None,
+            sender_alive: true,
+            receiver_alive: true,
+        }),
+        waiters: WaitQueue::new(),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+impl<T> Sender<T> {
+    /// Send `value`, or give it back if the receiver is gone.
+    pub fn send(self, value: T) -> Result<(), T> {
+        {
+            let mut state = self.shared.state.lock();
+            if !state.receiver_alive {
+                return Err(value);
+            }
+            state.value = Some(value);
+        }
+        self.shared.waiters.wake_all();
+        Ok(())
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().sender_alive = false;
+        self.shared.waiters.wake_all();
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Wait for the value, or fail if the sender is dropped without sending one.
+    pub async fn recv(self) -> Result<T, RecvError> {
+        self.shared
+            .waiters
+            .wait_until(|| {
+                let mut state = self.shared.state.lock();
+                match state.value.take() {
+                    Some(value) => Some(Ok(value)),
+                    None if !state.sender_alive => Some(Err(RecvError)),
+                    None => None,
+                }
+            })
+            .await
+    }
+
+    pub fn try_recv(&self) -> Option<T> {
+        self.shared.state.lock().value.take()
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().receiver_alive = false;
+    }
+}
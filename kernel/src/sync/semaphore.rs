@@ -0,0 +1,67 @@
+use crate::sync::WaitQueue;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A counting semaphore. Tasks wait in `acquire` until enough permits are available.
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Self {
+            permits: AtomicUsize::new(permits),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.permits.load(Ordering::Acquire)
+    }
+
+    /// Take `count` permits, waiting until they're available. They're given back when
+    /// the result is dropped.
+    pub async fn acquire(&self, count: usize) -> SemaphorePermit<'_> {
+        self.waiters.wait_until(|| self.try_acquire(count)).await
+    }
+
+    pub fn try_acquire(&self, count: usize) -> Option<SemaphorePermit<'_>> {
+        self.permits
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |permits| {
+                permits.checked_sub(count)
+            })
+            .ok()
+            .map(|_| SemaphorePermit {
+                semaphore: self,
+                count,
+            })
+    }
+
+    /// Add `count` permits, waking tasks that might now be able to take them.
+    pub fn add_permits(&self, count: usize) {
+        self.permits.fetch_add(count, Ordering::AcqRel);
+        // Waiters may want different numbers of permits, so let them all check
+        self.waiters.wake_all();
+    }
+}
+
+#[must_use = "permits are given back as soon as they're dropped"]
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    count: usize,
+}
+
+impl SemaphorePermit<'_> {
+    /// Keep the permits out of the semaphore for good.
+    pub fn forget(mut self) {
+        self.count = 0;
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        if self.count > 0 {
+            self.semaphore.add_permits(self.count);
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use alloc::collections::BTreeMap;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+struct Waiter {
+    waker: Option<Waker>,
+    woken: bool,
+}
+
+struct Inner {
+    next_id: u64,
+    /// Waiters in the order they started waiting.
+    waiters: BTreeMap<u64, Waiter>,
+}
+
+/// A queue of tasks waiting for something to happen.
+///
+/// A `Wait` joins the queue as soon as it's created rather than when first polled, so
+/// a wake between checking a condition and awaiting isn't lost. `wait_until` does that
+/// check for you.
+pub struct WaitQueue {
+    inner: spin::Mutex<Inner>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            inner: spin::Mutex::new(Inner {
+                next_id: 0,
+                waiters: BTreeMap::new(),
+            }),
+        }
+    }
+
+    /// Join the queue. The result completes once woken by `wake_one` or `wake_all`.
+    pub fn wait(&self) -> Wait<'_> {
+        let mut inner = self.inner.lock();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.waiters.insert(
+            id,
+            Waiter {
+                waker: None,
+                woken: false,
+            },
+        );
+        Wait { queue: self, id }
+    }
+
+    /// Wait until `condition` returns `Some`, checking it again each time we're woken.
+    pub async fn wait_until<T>(&self, mut condition: impl FnMut() -> Option<T>) -> T {
+        loop {
+            let wait = self.wait();
+            if let Some(value) = condition() {
+                return value;
+            }
+            wait.await;
+        }
+    }
+
+    /// Wake the longest waiting task that hasn't been woken yet. Returns whether there
+    /// was one.
+    pub fn wake_one(&self) -> bool {
+        let waker = {
+            let mut inner = self.inner.lock();
+            let Some(waiter) = inner.waiters.values_mut().find(|waiter| !waiter.woken) else {
+                return false;
+            };
+            waiter.woken = true;
+            waiter.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        true
+    }
+
+    /// Wake every waiting task.
+    pub fn wake_all(&self) {
+        let mut wakers = alloc::vec::Vec::new();
+        {
+            let mut inner = self.inner.lock();
+            for waiter in inner.waiters.values_mut() {
+                waiter.woken = true;
+                wakers.extend(waiter.waker.take());
+            }
+        }
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A place in a `WaitQueue`, leaving it when dropped.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Wait<'a> {
+    queue: &'a WaitQueue,
+    id: u64,
+}
+
+impl Future for Wait<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut inner = self.queue.inner.lock();
+        let waiter = inner.waiters.get_mut(&self.id).expect("waiter left the queue");
+        if waiter.woken {
+            inner.waiters.remove(&self.id);
+            Poll::Ready(())
+        } else {
+            waiter.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Wait<'_> {
+    fn drop(&mut self) {
+        let removed = self.queue.inner.lock().waiters.remove(&self.id);
+        // A wake we won't act on is passed on, so `wake_one` isn't lost
+        if removed.is_some_and(|waiter| waiter.woken) {
+            self.queue.wake_one();
+        }
+    }
+}
@@ -1,64 +1,425 @@
 use core::time::Duration;
+use crate::handle::Object;
 use crate::net::{socket, Socket};
 use crate::per_cpu::PerCpu;
+use crate::process::AddressSpace;
 use crate::print::print;
 use crate::println;
-use crate::{arch, process};
-use cardinal3_interface::{Error, Syscall, SyscallReturn};
+use crate::user_mem::{Plain, UserPtr, UserSlice};
+use crate::{arch, clock, ipc, pmm, process};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use cardinal3_interface::{
+    Clock, Error, MapFlags, MemoryInfo, Priority, RecvSize, SyscallArgs, SyscallNumber,
+    SyscallReturn, ABI_VERSION,
+};
 use crate::executor::sleep::sleep;
 
+/// A syscall decoded from registers. Pointer arguments still refer to userland memory
+/// and are only accessed through `user_mem`.
+#[derive(Debug)]
+enum Call {
+    AbiVersion,
+    Print(UserSlice<u8>),
+    Exit(u64),
+    Spawn(UserSlice<u8>, UserSlice<[u64; 2]>),
+    Wait(u64),
+    Sleep(u64),
+    DgSocket,
+    DgWrite(u64, u64, UserSlice<u8>),
+    DgRead(u64, UserSlice<u8>),
+    DgClose(u64),
+    DgBind(u64, u64),
+    Yield,
+    ChannelCreate(UserPtr<[u64; 2]>),
+    ChannelSend(u64, UserSlice<u8>, UserSlice<u64>),
+    ChannelRecv(u64, UserSlice<u8>, UserSlice<u64>),
+    ChannelClose(u64),
+    Close(u64),
+    Duplicate(u64),
+    SetPriority(u64, Priority),
+    SetAffinity(u64, u64),
+    CpuCount,
+    ClockGet(Clock),
+    MemoryInfo(UserPtr<MemoryInfo>),
+    Map(u64, MapFlags),
+    Unmap(u64, u64),
+    Protect(u64, u64, MapFlags),
+    Fork,
+}
+
 pub fn handle_syscall(frame: &mut arch::InterruptFrame) {
-    let syscall = frame.syscall_info();
     let task_id = frame.task_id();
-    let tasks_to_wake = frame.tasks_to_wake();
     let pid = PerCpu::running().expect("syscall without running process!");
+    let space = process::with(pid, |proc| proc.address_space().clone()).unwrap();
+
+    let tasks_to_wake = match frame.tasks_to_wake() {
+        Ok(tasks_to_wake) => tasks_to_wake,
+        Err(err) => {
+            frame.set_syscall_return(SyscallReturn::Error(err));
+            frame.set_tasks_to_wake_count(0);
+            return;
+        }
+    };
+
+    let result = match decode(frame.syscall_args()) {
+        Ok(call) => dispatch(pid, &space, frame, task_id, tasks_to_wake, call)
+            .unwrap_or_else(SyscallReturn::Error),
+        Err(err) => {
+            println!(
+                "[cpu:{} pid:{} bad syscall:{:x?} error:{:?}]",
+                arch::cpu_num(),
+                pid,
+                frame.syscall_args(),
+                err,
+            );
+            SyscallReturn::Error(err)
+        }
+    };
+
+    let count = process::with(pid, |proc| {
+        // If the wake buffer isn't writeable the ids stay queued for the next syscall.
+        let count = proc.drain_tasks_to_wake(&tasks_to_wake).unwrap_or(0);
+        if count > 0 {
+            // in case this was a call to Yield and we already have work to do
+            proc.unwait();
+        }
+        count
+    }).unwrap();
+
+    frame.set_syscall_return(result);
+    frame.set_tasks_to_wake_count(count);
+}
 
-    match syscall {
-        Syscall::Print(arg) => print!("{}", arg),
-        _ => println!(
+fn dispatch(
+    pid: u64,
+    space: &AddressSpace,
+    frame: &arch::InterruptFrame,
+    task_id: u64,
+    tasks_to_wake: UserSlice<u64>,
+    call: Call,
+) -> Result<SyscallReturn, Error> {
+    if !matches!(call, Call::Print(_)) {
+        println!(
             "[cpu:{} pid:{} syscall:{:?} tsc:{}]",
             arch::cpu_num(),
             pid,
-            syscall,
+            call,
             arch::rdtsc(),
-        ),
+        );
     }
 
-    let result = match syscall {
-        Syscall::Print(_) => SyscallReturn::Complete(0),
-        Syscall::Exit(code) => {
-            process::exit(*code);
+    let result = match call {
+        Call::AbiVersion => SyscallReturn::Complete(ABI_VERSION),
+        Call::Print(arg) => {
+            print!("{}", arg.read_str(space)?);
+            SyscallReturn::Complete(0)
+        }
+        Call::Exit(code) => {
+            process::exit(code);
             SyscallReturn::Complete(0)
         }
-        Syscall::Spawn(name, arg) => SyscallReturn::Complete(process::spawn(name, *arg)),
-        Syscall::DgSocket => SyscallReturn::Complete(Socket::new()),
-        Syscall::DgRead(sn, buf) => socket::read(*sn, buf),
-        Syscall::DgWrite(sn, buf) => socket::write(*sn, buf),
-        &Syscall::Sleep(usec) => {
+        Call::Spawn(name, args) => {
+            let args = read_str_array(space, args)?;
+            let child = process::spawn(&name.read_str(space)?, &args, pid)?;
+            SyscallReturn::Complete(insert(pid, Object::Process(child)))
+        }
+        Call::Wait(child) => {
+            let child = object(pid, child)?.process()?;
+            process::wait_for(pid, child, task_id)?
+        }
+        Call::DgSocket => SyscallReturn::Complete(insert(pid, Object::Socket(Socket::new()))),
+        Call::DgRead(sn, buf) => {
+            let sn = object(pid, sn)?.socket()?;
+            buf.check(space, true)?;
+            match socket::read(pid, task_id, sn)? {
+                Some(packet) => {
+                    // The full length tells the reader whether it was truncated
+                    let len = packet.data.len().min(buf.len());
+                    buf.write(space, &packet.data[..len])?;
+                    SyscallReturn::Complete(packet.data.len() as u64)
+                }
+                None => SyscallReturn::NotComplete,
+            }
+        }
+        Call::DgWrite(sn, dest, buf) => {
+            let sn = object(pid, sn)?.socket()?;
+            if buf.len() > socket::MAX_DATAGRAM {
+                return Err(Error::InvalidArgument);
+            }
+            socket::write(sn, dest, &buf.read(space)?)?
+        }
+        Call::DgBind(sn, addr) => {
+            socket::bind(object(pid, sn)?.socket()?, addr)?;
+            SyscallReturn::Complete(0)
+        }
+        Call::DgClose(sn) => {
+            close(pid, sn, Object::socket)?;
+            SyscallReturn::Complete(0)
+        }
+        Call::Sleep(usec) => {
             PerCpu::executor_mut().spawn(async move {
                 sleep(Duration::from_micros(usec)).await;
                 process::schedule_wakeup(pid, task_id);
             });
             SyscallReturn::NotComplete
         }
-        Syscall::Yield => {
+        Call::Yield => {
             process::with(pid, |proc| {
-                proc.wait(frame);
+                proc.wait(tasks_to_wake);
             });
             SyscallReturn::Complete(0)
         }
+        Call::ChannelCreate(out) => {
+            let (a, b) = ipc::create();
+            let handles = [insert(pid, Object::Channel(a)), insert(pid, Object::Channel(b))];
+            if let Err(err) = out.write(space, handles) {
+                close(pid, handles[0], Object::channel).unwrap();
+                close(pid, handles[1], Object::channel).unwrap();
+                return Err(err);
+            }
+            SyscallReturn::Complete(0)
+        }
+        Call::ChannelSend(ch, data, handles) => {
+            if data.len() > ipc::MAX_MESSAGE || handles.len() > ipc::MAX_HANDLES {
+                return Err(Error::InvalidArgument);
+            }
+            let ch = object(pid, ch)?.channel()?;
+            let handles = handles.read(space)?;
+            if (1..handles.len()).any(|i| handles[..i].contains(&handles[i])) {
+                return Err(Error::InvalidArgument);
+            }
+            let objects = handles
+                .iter()
+                .map(|&handle| object(pid, handle))
+                .collect::<Result<_, _>>()?;
+            let message = ipc::Message {
+                data: data.read(space)?,
+                handles: objects,
+            };
+            match ipc::send(pid, task_id, ch, message)? {
+                true => {
+                    // The message holds its own references, so these are never the last.
+                    let sent = process::with(pid, |proc| {
+                        handles
+                            .iter()
+                            .map(|&handle| proc.handles().remove(handle))
+                            .collect::<Vec<_>>()
+                    });
+                    drop(sent);
+                    SyscallReturn::Complete(0)
+                }
+                false => SyscallReturn::NotComplete,
+            }
+        }
+        Call::ChannelRecv(ch, buf, handles) => {
+            let ch = object(pid, ch)?.channel()?;
+            buf.check(space, true)?;
+            handles.check(space, true)?;
+            match ipc::recv(pid, task_id, ch, buf.len(), handles.len())? {
+                Some(mut message) => {
+                    let received = process::with(pid, |proc| {
+                        message
+                            .handles
+                            .drain(..)
+                            .map(|object| proc.handles().insert(object))
+                            .collect::<Vec<_>>()
+                    })
+                    .unwrap();
+                    // Both buffers were checked above, so the message can't be lost
+                    // to a bad address here.
+                    buf.write(space, &message.data)?;
+                    handles.write(space, &received)?;
+                    let size = RecvSize {
+                        bytes: message.data.len() as u32,
+                        handles: received.len() as u32,
+                    };
+                    SyscallReturn::Complete(size.into())
+                }
+                None => SyscallReturn::NotComplete,
+            }
+        }
+        Call::ChannelClose(ch) => {
+            close(pid, ch, Object::channel)?;
+            SyscallReturn::Complete(0)
+        }
+        Call::Close(handle) => {
+            close(pid, handle, |_| Ok(0))?;
+            SyscallReturn::Complete(0)
+        }
+        Call::SetPriority(target, priority) => {
+            process::set_priority(pid, target_process(pid, target)?, priority)?;
+            SyscallReturn::Complete(0)
+        }
+        Call::SetAffinity(target, cpus) => {
+            process::set_affinity(target_process(pid, target)?, cpus)?;
+            SyscallReturn::Complete(0)
+        }
+        Call::CpuCount => SyscallReturn::Complete(PerCpu::online_count() as u64),
+        Call::ClockGet(clock) => SyscallReturn::Complete(clock::get(clock)),
+        Call::MemoryInfo(out) => {
+            out.write(space, pmm::info())?;
+            SyscallReturn::Complete(0)
+        }
+        Call::Map(len, flags) => {
+            SyscallReturn::Complete(space.map(len, flags)? as u64)
+        }
+        Call::Unmap(addr, len) => {
+            space.unmap(addr, len)?;
+            SyscallReturn::Complete(0)
+        }
+        Call::Protect(addr, len, flags) => {
+            space.protect(addr, len, flags)?;
+            SyscallReturn::Complete(0)
+        }
+        Call::Fork => {
+            let child = process::fork(pid, frame)?;
+            SyscallReturn::Complete(insert(pid, Object::Process(child)))
+        }
+        Call::Duplicate(handle) => {
+            let handle = process::with(pid, |proc| proc.handles().duplicate(handle)).unwrap()?;
+            SyscallReturn::Complete(handle)
+        }
         _ => SyscallReturn::Error(Error::InvalidSyscall),
     };
 
-    let count = process::with(pid, |proc| {
-        let count = proc.drain_tasks_to_wake(tasks_to_wake);
-        if count > 0 {
-            // in case this was a call to Yield and we already have work to do
-            proc.unwait();
+    Ok(result)
+}
+
+/// Look up `handle` in the handle table of `pid`.
+fn object(pid: u64, handle: u64) -> Result<Arc<Object>, Error> {
+    process::with(pid, |proc| proc.handles().get(handle)).unwrap()
+}
+
+/// The process a handle passed to `pid` refers to, where 0 means `pid` itself.
+fn target_process(pid: u64, handle: u64) -> Result<u64, Error> {
+    match handle {
+        0 => Ok(pid),
+        handle => object(pid, handle)?.process(),
+    }
+}
+
+/// Give `pid` a handle to a new object.
+fn insert(pid: u64, object: Object) -> u64 {
+    let object = Arc::new(object);
+    process::with(pid, |proc| proc.handles().insert(object.clone())).unwrap()
+}
+
+/// Close `handle` in `pid`, if `check` accepts the object behind it.
+fn close(pid: u64, handle: u64, check: fn(&Object) -> Result<u64, Error>) -> Result<(), Error> {
+    let object = process::with(pid, |proc| {
+        check(&*proc.handles().get(handle)?)?;
+        proc.handles().remove(handle)
+    })
+    .unwrap()?;
+    // This may be the last handle, and closing the object can take the process lock.
+    drop(object);
+    Ok(())
+}
+
+/// Read an array of `StrRef`s, each an address and a length, from userland.
+fn read_str_array(
+    space: &AddressSpace,
+    array: UserSlice<[u64; 2]>,
+) -> Result<Vec<String>, Error> {
+    array
+        .read(space)?
+        .into_iter()
+        .map(|[addr, len]| UserSlice::<u8>::new(addr, len)?.read_str(space))
+        .collect()
+}
+
+/// Decode the register form of a syscall, rejecting unknown syscall numbers and
+/// malformed arguments.
+fn decode(args: SyscallArgs) -> Result<Call, Error> {
+    let number = SyscallNumber::try_from(args.number).map_err(|_| Error::InvalidSyscall)?;
+    let mut args = Args::new(args);
+
+    let call = match number {
+        SyscallNumber::AbiVersion => Call::AbiVersion,
+        SyscallNumber::Print => Call::Print(args.slice()?),
+        SyscallNumber::Exit => Call::Exit(args.next()),
+        SyscallNumber::Spawn => Call::Spawn(args.slice()?, args.slice()?),
+        SyscallNumber::Sleep => Call::Sleep(args.next()),
+        SyscallNumber::DgSocket => Call::DgSocket,
+        SyscallNumber::DgWrite => Call::DgWrite(args.next(), args.next(), args.slice()?),
+        SyscallNumber::DgRead => Call::DgRead(args.next(), args.slice()?),
+        SyscallNumber::DgClose => Call::DgClose(args.next()),
+        SyscallNumber::Yield => Call::Yield,
+        SyscallNumber::Wait => Call::Wait(args.next()),
+        SyscallNumber::ChannelCreate => Call::ChannelCreate(args.ptr()?),
+        SyscallNumber::ChannelSend => {
+            Call::ChannelSend(args.next(), args.slice()?, args.slice()?)
         }
-        count
-    }).unwrap();
+        SyscallNumber::ChannelRecv => {
+            Call::ChannelRecv(args.next(), args.slice()?, args.slice()?)
+        }
+        SyscallNumber::ChannelClose => Call::ChannelClose(args.next()),
+        SyscallNumber::Close => Call::Close(args.next()),
+        SyscallNumber::Duplicate => Call::Duplicate(args.next()),
+        SyscallNumber::DgBind => Call::DgBind(args.next(), args.next()),
+        SyscallNumber::SetPriority => {
+            let target = args.next();
+            let priority = Priority::try_from(args.next()).map_err(|_| Error::InvalidArgument)?;
+            Call::SetPriority(target, priority)
+        }
+        SyscallNumber::SetAffinity => Call::SetAffinity(args.next(), args.next()),
+        SyscallNumber::CpuCount => Call::CpuCount,
+        SyscallNumber::ClockGet => {
+            Call::ClockGet(Clock::try_from(args.next()).map_err(|_| Error::InvalidArgument)?)
+        }
+        SyscallNumber::MemoryInfo => Call::MemoryInfo(args.ptr()?),
+        SyscallNumber::Map => {
+            let len = args.next();
+            let flags = MapFlags::try_from(args.next()).map_err(|_| Error::InvalidArgument)?;
+            Call::Map(len, flags)
+        }
+        SyscallNumber::Unmap => Call::Unmap(args.next(), args.next()),
+        SyscallNumber::Protect => {
+            let (addr, len) = (args.next(), args.next());
+            let flags = MapFlags::try_from(args.next()).map_err(|_| Error::InvalidArgument)?;
+            Call::Protect(addr, len, flags)
+        }
+        SyscallNumber::Fork => Call::Fork,
+    };
 
-    frame.set_syscall_return(result);
-    frame.set_tasks_to_wake_count(count);
+    args.finish()?;
+    Ok(call)
+}
+
+/// Hands out syscall argument registers in order.
+struct Args {
+    args: SyscallArgs,
+    used: usize,
+}
+
+impl Args {
+    fn new(args: SyscallArgs) -> Self {
+        Self { args, used: 0 }
+    }
+
+    fn next(&mut self) -> u64 {
+        let arg = self.args.args[self.used];
+        self.used += 1;
+        arg
+    }
+
+    fn ptr<T: Plain>(&mut self) -> Result<UserPtr<T>, Error> {
+        UserPtr::new(self.next())
+    }
+
+    fn slice<T: Plain>(&mut self) -> Result<UserSlice<T>, Error> {
+        let addr = self.next();
+        let len = self.next();
+        UserSlice::new(addr, len)
+    }
+
+    /// Arguments a syscall doesn't use must be zero, so they can be given a meaning later.
+    fn finish(self) -> Result<(), Error> {
+        if self.args.args[self.used..].iter().any(|&arg| arg != 0) {
+            return Err(Error::InvalidArgument);
+        }
+        Ok(())
+    }
 }
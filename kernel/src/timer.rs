@@ -1,75 +1,166 @@
+//! Per-CPU timer events, measured in ticks since boot.
+//!
+//! Ticks come from the monotonic clock rather than from counting interrupts, so the
+//! hardware timer only needs to fire when something is due: it's programmed one-shot
+//! for the earliest event, or for the end of the running process's time slice if that
+//! comes first. An idle CPU with no events doesn't wake up at all.
+
 use crate::per_cpu::PerCpu;
+use crate::{arch, ipi};
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
-use alloc::vec::Vec;
 use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::Waker;
+use core::time::Duration;
 
 struct TimerEvent {
-    callback: Box<dyn FnOnce()>,
-    waker: Option<core::task::Waker>,
+    callback: Option<Box<dyn FnOnce()>>,
+    waker: Option<Waker>,
+}
+
+/// An event registered with `insert_at` or `wake_at`, which can be cancelled until it
+/// fires.
+#[derive(Debug)]
+#[must_use = "dropping a TimerHandle doesn't cancel the event"]
+pub struct TimerHandle {
+    cpu: usize,
+    key: (u64, u64),
+}
+
+impl TimerHandle {
+    /// The tick the event fires at.
+    pub fn time(&self) -> u64 {
+        self.key.0
+    }
+
+    /// Remove the event if it hasn't fired yet. Events belong to the CPU they were
+    /// registered on, so cancelling from another CPU is done there by IPI.
+    pub fn cancel(self) {
+        if self.cpu == arch::cpu_num() {
+            PerCpu::timer_mut().remove(self.key);
+        } else {
+            let key = self.key;
+            ipi::submit_ipi_to_cpu(self.cpu, move || PerCpu::timer_mut().remove(key));
+        }
+    }
 }
 
 pub struct Timer {
-    ticks: AtomicU64,
     events: BTreeMap<(u64, u64), TimerEvent>,
+    /// When the running process should be preempted, if a process is running.
+    preempt_at: Option<u64>,
+    /// The tick the hardware timer is programmed to fire at.
+    armed: Option<u64>,
 }
 
 impl Timer {
     pub fn new() -> Self {
         Self {
-            ticks: AtomicU64::new(0),
             events: BTreeMap::new(),
+            preempt_at: None,
+            armed: None,
         }
     }
 
-    pub fn raw_insert(&mut self, time: u64, callback: Box<dyn FnOnce()>) {
+    fn raw_insert(&mut self, time: u64, event: TimerEvent) -> TimerHandle {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
 
-        let event = TimerEvent {
-            callback,
-            waker: None,
-        };
-
         let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
 
         self.events.insert((time, id), event);
+        self.program();
+        TimerHandle {
+            cpu: arch::cpu_num(),
+            key: (time, id),
+        }
     }
 
-    fn duration_to_ticks(duration: core::time::Duration) -> u64 {
-        duration.as_millis() as u64
+    fn remove(&mut self, key: (u64, u64)) {
+        if self.events.remove(&key).is_some() {
+            self.program();
+        }
     }
 
-    pub fn tick(&mut self) {
-        self.ticks.fetch_add(1, Ordering::SeqCst);
-        let up_to = self.ticks.load(Ordering::SeqCst);
+    /// Arrange for the timer to interrupt the running process at `time`, or stop doing
+    /// so with `None`.
+    pub fn set_preempt_at(&mut self, time: Option<u64>) {
+        self.preempt_at = time;
+        self.program();
+    }
 
-        let mut keys = Vec::new();
+    fn next_deadline(&self) -> Option<u64> {
+        let next_event = self.events.first_key_value().map(|(&(time, _), _)| time);
+        match (next_event, self.preempt_at) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
 
-        for (time, id) in self.events.keys() {
-            if *time <= up_to {
-                keys.push((*time, *id));
-            }
+    /// Reprogram the hardware timer if the earliest deadline changed.
+    fn program(&mut self) {
+        let next = self.next_deadline();
+        if next != self.armed {
+            self.armed = next;
+            arch::set_timer_deadline(next.map(|time| time * TICK.as_nanos() as u64));
         }
+    }
+
+    /// Run the events that are due, after a timer interrupt.
+    pub fn expire(&mut self) {
+        self.armed = None;
+        let now = self.ticks();
 
-        for key in keys {
-            let event = self.events.remove(&key).unwrap();
-            (event.callback)();
+        while let Some(entry) = self.events.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let event = entry.remove();
+            if let Some(callback) = event.callback {
+                callback();
+            }
             if let Some(waker) = event.waker {
                 waker.wake();
             }
         }
+        if self.preempt_at.is_some_and(|time| time <= now) {
+            self.preempt_at = None;
+        }
+
+        self.program();
     }
 
     pub fn ticks(&self) -> u64 {
-        self.ticks.load(Ordering::Relaxed)
+        ticks()
     }
 }
 
-pub fn ticks_for(duration: core::time::Duration) -> u64 {
-    Timer::duration_to_ticks(duration)
+/// The length of a tick.
+pub const TICK: Duration = Duration::from_millis(1);
+
+/// Ticks since boot, from the monotonic clock.
+pub fn ticks() -> u64 {
+    arch::nanos_since_boot() / TICK.as_nanos() as u64
+}
+
+/// The number of ticks that covers at least `duration`.
+pub fn ticks_for(duration: Duration) -> u64 {
+    (duration.as_nanos() as u64).div_ceil(TICK.as_nanos() as u64)
+}
+
+/// Call `callback` from the timer interrupt on this CPU at tick `time`.
+pub fn insert_at<F: FnOnce() + 'static>(time: u64, callback: F) -> TimerHandle {
+    let event = TimerEvent {
+        callback: Some(Box::new(callback)),
+        waker: None,
+    };
+    PerCpu::timer_mut().raw_insert(time, event)
 }
 
-pub fn insert_at<F: FnOnce() + 'static>(time: u64, callback: F) {
-    let callback = Box::new(callback);
-    PerCpu::timer_mut().raw_insert(time, callback);
+/// Wake `waker` from the timer interrupt on this CPU at tick `time`.
+pub fn wake_at(time: u64, waker: Waker) -> TimerHandle {
+    let event = TimerEvent {
+        callback: None,
+        waker: Some(waker),
+    };
+    PerCpu::timer_mut().raw_insert(time, event)
 }
@@ -0,0 +1,192 @@
+//! Checked access to userland memory.
+//!
+//! The kernel never dereferences pointers it gets from userland. Instead, syscall
+//! arguments are wrapped in a `UserPtr` or `UserSlice`, and every access walks the
+//! process's page tables and goes through the direct map, filling in pages the process
+//! hasn't touched yet. An address the process couldn't access itself (or write to, when
+//! writing) fails with `Error::BadAddress` instead of faulting in the kernel, and a page
+//! there's no memory to fill in fails with `Error::OutOfMemory`.
+
+use crate::arch;
+use crate::process::AddressSpace;
+use alloc::string::String;
+use alloc::vec::Vec;
+use cardinal3_interface::{Error, MemoryInfo};
+use core::cmp::min;
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of, MaybeUninit};
+
+/// Types that can be copied to and from userland byte-for-byte.
+///
+/// # Safety
+///
+/// Every bit pattern must be a valid value of the type.
+pub unsafe trait Plain: Copy {}
+
+unsafe impl Plain for u8 {}
+unsafe impl Plain for u32 {}
+unsafe impl Plain for u64 {}
+unsafe impl<T: Plain, const N: usize> Plain for [T; N] {}
+unsafe impl Plain for MemoryInfo {}
+
+#[derive(Debug, Copy, Clone)]
+pub struct UserPtr<T> {
+    addr: usize,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T: Plain> UserPtr<T> {
+    pub fn new(addr: u64) -> Result<Self, Error> {
+        let addr = check_range(addr, size_of::<T>(), align_of::<T>())?;
+        Ok(Self {
+            addr,
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn read(self, space: &AddressSpace) -> Result<T, Error> {
+        let mut value = MaybeUninit::<T>::uninit();
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>())
+        };
+        copy_from_user(space, self.addr, bytes)?;
+        Ok(unsafe { value.assume_init() })
+    }
+
+    pub fn write(self, space: &AddressSpace, value: T) -> Result<(), Error> {
+        copy_to_user(space, self.addr, as_bytes(core::slice::from_ref(&value)))
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct UserSlice<T> {
+    addr: usize,
+    len: usize,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T: Plain> UserSlice<T> {
+    pub fn new(addr: u64, len: u64) -> Result<Self, Error> {
+        let size = (len as usize)
+            .checked_mul(size_of::<T>())
+            .ok_or(Error::BadAddress)?;
+        let addr = check_range(addr, size, align_of::<T>())?;
+        Ok(Self {
+            addr,
+            len: len as usize,
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn size(&self) -> usize {
+        self.len * size_of::<T>()
+    }
+
+    /// Check that the whole slice is accessible, without copying anything.
+    pub fn check(&self, space: &AddressSpace, write: bool) -> Result<(), Error> {
+        for (addr, _, _) in chunks(self.addr, self.size()) {
+            space.physical_address(addr, write)?;
+        }
+        Ok(())
+    }
+
+    pub fn read(&self, space: &AddressSpace) -> Result<Vec<T>, Error> {
+        // Check first so a bogus length fails before we try to allocate for it.
+        self.check(space, false)?;
+
+        let mut data = Vec::<T>::with_capacity(self.len);
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(data.as_mut_ptr() as *mut u8, self.size())
+        };
+        copy_from_user(space, self.addr, bytes)?;
+        unsafe { data.set_len(self.len) };
+        Ok(data)
+    }
+
+    /// Write `data` to the start of the slice.
+    pub fn write(&self, space: &AddressSpace, data: &[T]) -> Result<(), Error> {
+        if data.len() > self.len {
+            return Err(Error::InvalidArgument);
+        }
+        copy_to_user(space, self.addr, as_bytes(data))
+    }
+}
+
+impl UserSlice<u8> {
+    pub fn read_str(&self, space: &AddressSpace) -> Result<String, Error> {
+        String::from_utf8(self.read(space)?).map_err(|_| Error::InvalidArgument)
+    }
+}
+
+fn as_bytes<T: Plain>(data: &[T]) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(data.as_ptr() as *const u8, size_of_val(data)) }
+}
+
+/// Validate that `size` bytes at `addr` are aligned and lie entirely in the user half
+/// of the address space.
+fn check_range(addr: u64, size: usize, align: usize) -> Result<usize, Error> {
+    let addr = addr as usize;
+    if addr == 0 || !addr.is_multiple_of(align) {
+        return Err(Error::BadAddress);
+    }
+    match addr.checked_add(size) {
+        Some(end) if end <= arch::USER_SPACE_END => Ok(addr),
+        _ => Err(Error::BadAddress),
+    }
+}
+
+/// Split `len` bytes at `addr` into `(address, offset, length)` pieces that don't
+/// cross page boundaries.
+fn chunks(addr: usize, len: usize) -> impl Iterator<Item = (usize, usize, usize)> {
+    let mut offset = 0;
+    core::iter::from_fn(move || {
+        if offset >= len {
+            return None;
+        }
+        let at = addr + offset;
+        let chunk = min(arch::PAGE_SIZE - (at & arch::PAGE_MASK), len - offset);
+        let res = (at, offset, chunk);
+        offset += chunk;
+        Some(res)
+    })
+}
+
+pub fn copy_from_user(space: &AddressSpace, addr: usize, dst: &mut [u8]) -> Result<(), Error> {
+    for (at, offset, len) in chunks(addr, dst.len()) {
+        let phy = space.physical_address(at, false)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                arch::direct_map_offset(phy) as *const u8,
+                dst[offset..].as_mut_ptr(),
+                len,
+            );
+        }
+    }
+    Ok(())
+}
+
+pub fn copy_to_user(space: &AddressSpace, addr: usize, src: &[u8]) -> Result<(), Error> {
+    // Check everything up front so a failure doesn't leave a partial write behind.
+    for (at, _, _) in chunks(addr, src.len()) {
+        space.physical_address(at, true)?;
+    }
+    for (at, offset, len) in chunks(addr, src.len()) {
+        let phy = space.physical_address(at, true)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                src[offset..].as_ptr(),
+                arch::direct_map_offset(phy) as *mut u8,
+                len,
+            );
+        }
+    }
+    Ok(())
+}
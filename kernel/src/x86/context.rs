@@ -2,7 +2,8 @@ use crate::arch::cpu_num;
 use crate::per_cpu::PerCpu;
 use crate::x86;
 use bitflags::bitflags;
-use cardinal3_interface::SyscallReturn;
+use crate::user_mem::UserSlice;
+use cardinal3_interface::{Error, SyscallArgs, SyscallReturn};
 use core::arch::asm;
 use core::fmt::Debug;
 use core::fmt::Formatter;
@@ -74,32 +75,23 @@ impl InterruptFrame {
         }
     }
 
-    pub fn syscall_info(&self) -> &cardinal3_interface::Syscall {
-        unsafe { &*(self.rax as *const cardinal3_interface::Syscall) }
+    pub fn syscall_args(&self) -> SyscallArgs {
+        SyscallArgs {
+            number: self.rax,
+            args: [self.r8, self.r9, self.r10, self.r11, self.r12, self.r13],
+        }
     }
 
     pub fn task_id(&self) -> u64 {
         self.rdi
     }
 
-    pub fn tasks_to_wake(&self) -> &'static mut [u64] {
-        unsafe { core::slice::from_raw_parts_mut(self.rsi as *mut u64, self.rdx as usize) }
+    pub fn tasks_to_wake(&self) -> Result<UserSlice<u64>, Error> {
+        UserSlice::new(self.rsi, self.rdx)
     }
 
     pub fn set_syscall_return(&mut self, value: SyscallReturn) {
-        match value {
-            SyscallReturn::Complete(v) => {
-                self.rax = 0;
-                self.rdi = v;
-            }
-            SyscallReturn::NotComplete => {
-                self.rax = 1;
-            }
-            SyscallReturn::Error(v) => {
-                self.rax = 2;
-                self.rdi = v as u64;
-            }
-        }
+        (self.rax, self.rdi) = value.encode();
     }
 
     pub fn set_tasks_to_wake_count(&mut self, count: usize) {
@@ -184,6 +176,10 @@ impl Context {
         res
     }
 
+    pub fn set_user_sp(&mut self, sp: u64) {
+        self.frame.user_sp = sp;
+    }
+
     pub fn set_arg1(&mut self, arg1: u64) {
         self.frame.rdi = arg1;
     }
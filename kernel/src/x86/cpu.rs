@@ -1,7 +1,9 @@
 use crate::per_cpu::PerCpu;
+use crate::pmm;
 use crate::x86::gdt;
 use crate::x86::gdt::Tss;
-use crate::NUM_CPUS;
+use crate::x86::lapic;
+use crate::x86::{direct_map_offset, PAGE_SIZE};
 use core::arch::asm;
 
 pub fn cpuid(a: u32, c: u32) -> [u32; 4] {
@@ -23,8 +25,8 @@ pub fn cpuid(a: u32, c: u32) -> [u32; 4] {
 }
 
 pub const IA32_LAPIC_BASE: u32 = 27;
+pub const IA32_GS_BASE: u32 = 0xc000_0101;
 
-#[allow(dead_code)]
 pub unsafe fn wrmsr(msr: u32, value: u64) {
     asm!(
         "wrmsr",
@@ -34,7 +36,6 @@ pub unsafe fn wrmsr(msr: u32, value: u64) {
     );
 }
 
-#[allow(dead_code)]
 pub unsafe fn rdmsr(msr: u32) -> u64 {
     let value_low: u64;
     let value_high: u64;
@@ -58,8 +59,36 @@ pub fn cr2() -> u64 {
     value
 }
 
+/// This CPU's local APIC ID. Only the x2APIC leaf has the full 32-bit ID.
+pub fn apic_id() -> u32 {
+    if lapic::x2apic() {
+        cpuid(0xb, 0)[3]
+    } else {
+        cpuid(1, 0)[1] >> 24
+    }
+}
+
+/// This CPU's logical number.
 pub fn cpu_num() -> usize {
-    (cpuid(1, 0)[1] >> 24) as usize
+    PerCpu::cpu_num()
+}
+
+/// Point GS at this CPU's `PerCpu`, whose first field must be a pointer to itself.
+pub unsafe fn set_cpu_local(ptr: usize) {
+    wrmsr(IA32_GS_BASE, ptr as u64);
+}
+
+/// The pointer set by `set_cpu_local`.
+pub fn cpu_local() -> usize {
+    let value: usize;
+    unsafe {
+        asm!(
+            "mov {}, gs:[0]",
+            out(reg) value,
+            options(nostack, readonly, preserves_flags),
+        );
+    }
+    value
 }
 
 #[derive(Copy, Clone)]
@@ -67,8 +96,8 @@ pub struct Cpu {
     initialized: bool,
     gdt: [u64; 7],
     tss: Tss,
-    stack: *const Stack,
-    df_stack: *const Stack,
+    stack_top: usize,
+    df_stack_top: usize,
 }
 
 impl Cpu {
@@ -77,8 +106,8 @@ impl Cpu {
             initialized: false,
             gdt: [0; 7],
             tss: Tss::new(),
-            stack: core::ptr::null(),
-            df_stack: core::ptr::null(),
+            stack_top: 0,
+            df_stack_top: 0,
         }
     }
 
@@ -91,13 +120,17 @@ impl Cpu {
 
         gdt::init_in_place(&mut self.gdt, &mut self.tss);
 
-        self.stack = unsafe { &STACKS[cpu_num].0 } as *const _;
-        self.df_stack = unsafe { &STACKS[cpu_num].1 } as *const _;
+        if cpu_num == 0 {
+            let stacks = unsafe { &*core::ptr::addr_of!(BSP_STACKS) };
+            self.stack_top = stacks.0.top();
+            self.df_stack_top = stacks.1.top();
+        } else {
+            self.stack_top = Stack::alloc_top();
+            self.df_stack_top = Stack::alloc_top();
+        }
 
-        self.tss
-            .set_kernel_stack(unsafe { (*self.stack).top() as u64 });
-        self.tss
-            .set_df_stack(unsafe { (*self.df_stack).top() as u64 });
+        self.tss.set_kernel_stack(self.stack_top as u64);
+        self.tss.set_df_stack(self.df_stack_top as u64);
     }
 
     fn use_(&self) {
@@ -105,7 +138,9 @@ impl Cpu {
     }
 }
 
-static mut STACKS: [(Stack, Stack); NUM_CPUS] = [(Stack::new(), Stack::new()); NUM_CPUS];
+/// The BSP's stacks. Other CPUs' stacks are allocated when they're set up, since the
+/// number of CPUs isn't known until boot.
+static mut BSP_STACKS: (Stack, Stack) = (Stack::new(), Stack::new());
 
 #[repr(align(16))]
 #[derive(Copy, Clone)]
@@ -121,11 +156,20 @@ impl Stack {
     pub fn top(&self) -> usize {
         self.0.as_ptr() as usize + Self::SIZE
     }
+
+    /// Allocate a stack from physical memory and return its top in the direct map.
+    fn alloc_top() -> usize {
+        let phys = pmm::alloc_contiguous(Self::SIZE / PAGE_SIZE, pmm::Owner::Kernel)
+            .expect("out of memory for CPU stacks");
+        direct_map_offset(phys) + Self::SIZE
+    }
 }
 
 pub unsafe fn use_() {
     let cpu = PerCpu::arch();
     cpu.use_();
+    // Loading a selector into GS can clear its base, which holds the `PerCpu` pointer.
+    let gs_base = rdmsr(IA32_GS_BASE);
     asm!(
         "mov ds, ax",
         "mov es, ax",
@@ -134,6 +178,7 @@ pub unsafe fn use_() {
         "mov ss, ax",
         in("ax") 0,
     );
+    wrmsr(IA32_GS_BASE, gs_base);
 }
 
 pub fn kernel_stack() -> u64 {
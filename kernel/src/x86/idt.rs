@@ -155,6 +155,11 @@ pub(super) unsafe fn load() {
 #[no_mangle]
 unsafe extern "C" fn interrupt_shim() {
     naked_asm!(
+        // Swap in the kernel's GS base if we came from user mode
+        "test qword ptr [rsp + 24], 3",
+        "jz 2f",
+        "swapgs",
+        "2:",
         "push rax",
         "push rbx",
         "push rcx",
@@ -200,6 +205,10 @@ unsafe extern "C" fn interrupt_shim() {
         "pop rbx",
         "pop rax",
         "add rsp, 16",
+        "test qword ptr [rsp + 8], 3",
+        "jz 3f",
+        "swapgs",
+        "3:",
         "iretq",
     );
 }
@@ -6,6 +6,7 @@ use crate::x86::context::InterruptFrame;
 use crate::x86::cpu::cpu_num;
 use crate::x86::{cpu, lapic, print_backtrace_from_frame, sleep_forever_no_irq, SERIAL};
 use crate::{arch, executor, process, syscalls};
+use cardinal3_interface::EXIT_FAULT;
 use core::arch::asm;
 use core::sync::atomic::{AtomicU64, Ordering};
 
@@ -40,7 +41,7 @@ unsafe extern "C" fn rs_interrupt_shim(frame: *mut InterruptFrame) {
         let pid = old_pid.expect("Interrupt from usermode with no process on CPU");
         let should_run = process::with(pid, |p| {
             p.set_context(frame);
-            p.set_on_cpu(None);
+            p.account();
             p.should_run()
         })
         .expect("Interrupt from usermode with process that no longer exists");
@@ -50,23 +51,25 @@ unsafe extern "C" fn rs_interrupt_shim(frame: *mut InterruptFrame) {
         match should_run {
             ProcessDisposition::MayContinue => {}
             ProcessDisposition::TimesUp => {
+                // Returns only if this process was picked to carry on.
                 process::maybe_run_usermode_program(true);
             }
             ProcessDisposition::NotNow => {
+                process::deschedule(pid);
                 process::maybe_run_usermode_program(false);
-                arch::sleep_forever();
+                process::idle_loop();
             }
             ProcessDisposition::NeverAgain => {
+                process::deschedule(pid);
                 executor::spawn(async move {
-                    process::remove(pid);
+                    process::retire(pid);
                 });
                 process::maybe_run_usermode_program(false);
-                arch::sleep_forever();
+                process::idle_loop();
             }
         }
 
         arch::load_tree(old_vm_root.expect("Returning to process with no vm_root"));
-        process::with(pid, |p| p.set_on_cpu(Some(cpu_num())));
     } else {
         process::maybe_run_usermode_program(false);
     }
@@ -82,8 +85,24 @@ fn handle_debug(frame: &InterruptFrame) {
     println!("step ip: {:x}", frame.ip);
 }
 
+/// Fill in a user page on first touch. A fault the process can't recover from kills
+/// just that process; one in the kernel is a bug.
 fn handle_page_fault(frame: &InterruptFrame) {
+    if frame.cs & 0x03 == 0x03 {
+        let pid = PerCpu::running().expect("Page fault from usermode with no process on CPU");
+        let write = frame.error_code & 0x2 != 0;
+        let Err(fault) = process::handle_page_fault(pid, cpu::cr2() as usize, write) else {
+            return;
+        };
+        println!("[cpu:{} pid:{} killed by {:?} fault]", cpu_num(), pid, fault);
+        report_page_fault(frame, frame.error_code, cpu::cr2());
+        process::kill(pid, EXIT_FAULT);
+        return;
+    }
+
     report_page_fault(frame, frame.error_code, cpu::cr2());
+    println!("access came from here:");
+    print_backtrace_from_frame(frame);
     panic!("Unhandled page fault\n{}", frame);
 }
 
@@ -128,9 +147,6 @@ fn report_page_fault(frame: &InterruptFrame, error_code: u64, _fault_addr: u64)
         reason,
         mode
     );
-
-    println!("access came from here:");
-    print_backtrace_from_frame(frame);
 }
 
 fn handle_irq(frame: &mut InterruptFrame) {
@@ -146,7 +162,7 @@ fn handle_irq(frame: &mut InterruptFrame) {
 }
 
 fn handle_timer(_frame: &InterruptFrame) {
-    PerCpu::timer_mut().tick();
+    PerCpu::timer_mut().expire();
     PerCpu::executor_mut().do_work();
 }
 
@@ -160,6 +176,7 @@ fn handle_syscall(frame: &mut InterruptFrame) {
 
 fn handle_ipi(_frame: &InterruptFrame) {
     handle_ipi_irq();
+    PerCpu::executor_mut().do_work();
 
     lapic::eoi();
 }
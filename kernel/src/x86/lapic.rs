@@ -1,58 +1,192 @@
+//! The local APIC, in x2APIC mode (through MSRs) when the bootloader enabled it and in
+//! xAPIC mode (through MMIO) otherwise.
+//!
+//! The timer is calibrated once against the PIT and only ever used one-shot, armed for
+//! the next deadline: in TSC-deadline mode when the CPU supports it, and by counting
+//! down otherwise.
+
+use crate::cmdline;
+use crate::limine::{self, smp::LimineSmpResponse};
 use crate::print::println;
-use crate::x86::{cpu, direct_map_offset};
-use spin::Lazy;
+use crate::x86::{cpu, direct_map_offset, pit, rdtsc, tsc};
+use spin::{Lazy, Once};
 
 pub const DEFAULT_ADDRESS: u64 = 0xfee0_0000;
 pub static MAPPED_ADDRESS: Lazy<usize> = Lazy::new(|| direct_map_offset(DEFAULT_ADDRESS));
 
+const EOI: isize = 0xb0;
+const SPURIOUS: isize = 0xf0;
+const ICR_LOW: isize = 0x300;
+const ICR_HIGH: isize = 0x310;
+const LVT_TIMER: isize = 0x320;
+const TIMER_INITIAL: isize = 0x380;
+const TIMER_CURRENT: isize = 0x390;
+const TIMER_DIVIDE: isize = 0x3e0;
+
+const X2APIC_MSR_BASE: u32 = 0x800;
+const IA32_TSC_DEADLINE: u32 = 0x6e0;
+
+const TIMER_VECTOR: u32 = 0x20;
+const TIMER_MASKED: u32 = 1 << 16;
+const TIMER_TSC_DEADLINE: u32 = 2 << 17;
+const DIVIDE_BY_16: u32 = 0b0011;
+
+/// How long to measure the timers against the PIT for.
+const CALIBRATION_MS: u64 = 10;
+
+/// Whether the bootloader put the local APICs in x2APIC mode.
+static X2APIC: Lazy<bool> = Lazy::new(|| {
+    let smp = unsafe { &**limine::SMP.response.get() };
+    smp.flags & LimineSmpResponse::ENABLED_X2APIC != 0
+});
+
+static CALIBRATION: Once<Calibration> = Once::new();
+
+/// What `calibrate` measured. All CPUs are assumed to share it.
+struct Calibration {
+    /// LAPIC timer counts per second with a divider of 16.
+    lapic_hz: u64,
+    tsc_deadline: bool,
+}
+
+pub fn x2apic() -> bool {
+    *X2APIC
+}
+
 unsafe fn relocate() {
     cpu::wrmsr(cpu::IA32_LAPIC_BASE, DEFAULT_ADDRESS as u64 | 1 << 11);
 }
 
 pub fn init() {
     unsafe {
-        relocate();
-        write(0xf0, read(0xf0) | 0x100);
+        // Writing the base without the x2APIC bit would be an invalid mode transition
+        if !x2apic() {
+            relocate();
+        }
+        write(SPURIOUS, read(SPURIOUS) | 0x100);
     }
+    CALIBRATION.call_once(|| unsafe { calibrate() });
 }
 
 pub unsafe fn read(offset: isize) -> u32 {
+    if x2apic() {
+        return cpu::rdmsr(X2APIC_MSR_BASE + (offset >> 4) as u32) as u32;
+    }
     let ptr = (MAPPED_ADDRESS.clone() as *mut u32).byte_offset(offset);
     ptr.read_volatile()
 }
 
 pub unsafe fn write(offset: isize, value: u32) {
+    if x2apic() {
+        cpu::wrmsr(X2APIC_MSR_BASE + (offset >> 4) as u32, value as u64);
+        return;
+    }
     let ptr = (MAPPED_ADDRESS.clone() as *mut u32).byte_offset(offset);
     ptr.write_volatile(value);
 }
 
+/// Send an interrupt command. In x2APIC mode the ICR is a single 64-bit register with the
+/// full destination ID in its high half.
+unsafe fn write_icr(apic_id: u32, command: u32) {
+    if x2apic() {
+        let icr = (apic_id as u64) << 32 | command as u64;
+        cpu::wrmsr(X2APIC_MSR_BASE + (ICR_LOW >> 4) as u32, icr);
+    } else {
+        write(ICR_HIGH, apic_id << 24);
+        write(ICR_LOW, command);
+    }
+}
+
 pub fn eoi() {
     unsafe {
-        write(0xb0, 0);
+        write(EOI, 0);
     }
 }
 
-#[allow(unused)]
-pub fn send_ipi(apic_id: u8, vector: u8) {
+pub fn send_ipi(apic_id: u32, vector: u8) {
     unsafe {
-        write(0x310, (apic_id as u32) << 24);
-        write(0x300, vector as u32 | 1 << 14);
+        write_icr(apic_id, vector as u32 | 1 << 14);
     }
 }
 
 pub fn broadcast_ipi(vector: u8) {
     unsafe {
         // send ipi to all other CPUS except self
-        write(0x310, 0);
-        write(0x300, vector as u32 | 1 << 14 | 3 << 18);
+        write_icr(0, vector as u32 | 1 << 14 | 3 << 18);
     }
 }
 
+fn tsc_deadline_supported() -> bool {
+    cpu::cpuid(1, 0)[2] & 1 << 24 != 0
+}
+
+/// Measure the LAPIC timer and the TSC against the PIT, start the TSC clock and pick the
+/// timer mode. The TSC-deadline timer is used when the CPU has it and the TSC is
+/// invariant, unless `tsc_deadline=no` is passed.
+unsafe fn calibrate() -> Calibration {
+    write(TIMER_DIVIDE, DIVIDE_BY_16);
+    write(LVT_TIMER, TIMER_MASKED | TIMER_VECTOR);
+
+    let tsc_start = rdtsc();
+    write(TIMER_INITIAL, u32::MAX);
+    pit::wait_cycles((pit::FREQUENCY * CALIBRATION_MS / 1000) as u16);
+    let lapic_elapsed = u32::MAX - read(TIMER_CURRENT);
+    let tsc_elapsed = rdtsc() - tsc_start;
+    write(TIMER_INITIAL, 0);
+
+    tsc::init(tsc_elapsed * 1000 / CALIBRATION_MS);
+    let invariant = tsc::invariant();
+    if !invariant {
+        println!("lapic: tsc isn't invariant, the clock may drift");
+    }
+    let tsc_deadline = tsc_deadline_supported()
+        && invariant
+        && cmdline::get("tsc_deadline") != Some("no");
+    let calibration = Calibration {
+        lapic_hz: lapic_elapsed as u64 * 1000 / CALIBRATION_MS,
+        tsc_deadline,
+    };
+    println!(
+        "lapic: x2apic {}, timer {} Hz, tsc {} Hz, {} mode",
+        x2apic(),
+        calibration.lapic_hz,
+        tsc::frequency(),
+        if tsc_deadline { "tsc-deadline" } else { "one-shot" },
+    );
+    calibration
+}
+
+/// Put the timer in one-shot mode, disarmed until `set_deadline`.
 pub fn start_timer() {
     println!("starting timer");
+    let calibration = CALIBRATION.get().expect("timer started before calibration");
+    unsafe {
+        if calibration.tsc_deadline {
+            write(LVT_TIMER, TIMER_TSC_DEADLINE | TIMER_VECTOR);
+        } else {
+            write(LVT_TIMER, TIMER_VECTOR);
+            write(TIMER_DIVIDE, DIVIDE_BY_16);
+        }
+    }
+}
+
+/// Arm the timer to fire `nanos` after boot, or disarm it with `None`. A deadline that
+/// has already passed fires straight away.
+pub fn set_deadline(nanos: Option<u64>) {
+    let Some(calibration) = CALIBRATION.get() else {
+        return;
+    };
     unsafe {
-        write(0x320, 1 << 17 | 0x20);
-        write(0x3e0, 0b1011);
-        write(0x380, 1_000_000);
+        if calibration.tsc_deadline {
+            cpu::wrmsr(IA32_TSC_DEADLINE, nanos.map_or(0, tsc::at_nanos));
+            return;
+        }
+        let count = nanos.map_or(0, |nanos| {
+            let remaining = nanos.saturating_sub(tsc::nanos()) as u128;
+            // A count too large to fit just fires early, and the timer is armed again
+            let count = remaining * calibration.lapic_hz as u128 / 1_000_000_000;
+            count.clamp(1, u32::MAX as u128) as u32
+        });
+        write(TIMER_INITIAL, count);
     }
 }
@@ -47,6 +47,7 @@ pub unsafe fn long_jump_usermode(jump_to: usize, stack: usize) -> ! {
         "mov rdi, 0",
         "mov rsi, 0",
 
+        "swapgs",
         "iretq",
         in("rdi") jump_to,
         in("rsi") stack,
@@ -92,6 +93,11 @@ pub unsafe fn long_jump_context(context: *const Context) -> ! {
         "pop rbx",
         "pop rax",
         "add rsp, 16",
+        // Leave the kernel's GS base behind if we're going to user mode
+        "test qword ptr [rsp + 8], 3",
+        "jz 3f",
+        "swapgs",
+        "3:",
         "iretq",
         context = in(reg) context,
         options(noreturn)
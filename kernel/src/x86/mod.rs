@@ -1,5 +1,6 @@
 use crate::limine;
 use crate::pci::PciAddress;
+use crate::per_cpu::PerCpu;
 use crate::print::println;
 use core::arch::asm;
 use core::sync::atomic::{AtomicBool, Ordering};
@@ -17,12 +18,19 @@ mod long_jump;
 mod page;
 mod pic;
 mod pio;
+mod pit;
+mod rtc;
 mod serial;
+mod tsc;
 
 pub use context::{Context, InterruptFrame};
-pub use cpu::{cpu_num, Cpu};
+pub use cpu::{apic_id, cpu_local, cpu_num, set_cpu_local, Cpu};
 pub use long_jump::{long_jump_context, long_jump_cs};
-pub use page::{free_tree, load_tree, map_in_table, new_tree, physical_address, PageTable};
+pub use page::{
+    copy_on_write_page, free_tree, kernel_table_pages, load_tree, map_in_table, new_tree,
+    physical_address, protect_in_table, share_in_table, unmap_in_table,
+    user_physical_address, PageTable,
+};
 pub use serial::SERIAL;
 
 static DIRECT_MAP_OFFSET: Lazy<usize> =
@@ -32,9 +40,17 @@ static SYSTEM_INIT_DONE: AtomicBool = AtomicBool::new(false);
 pub const PAGE_SIZE: usize = 0x1000;
 pub const PAGE_MASK: usize = 0xfff;
 
-pub const USER_STACK_BASE: usize = 0x0000_7fff_ff00_0000;
-pub const USER_STACK_PAGES: usize = 16;
-pub const USER_STACK_TOP: usize = USER_STACK_BASE + USER_STACK_PAGES * PAGE_SIZE;
+pub const USER_SPACE_END: usize = 0x0000_8000_0000_0000;
+
+/// The stack grows down from its top as it's touched, to at most `USER_STACK_PAGES`.
+/// The page below its base is a guard page that's never mapped.
+pub const USER_STACK_TOP: usize = 0x0000_7fff_ff01_0000;
+pub const USER_STACK_PAGES: usize = 256;
+pub const USER_STACK_BASE: usize = USER_STACK_TOP - USER_STACK_PAGES * PAGE_SIZE;
+
+/// Where the `Map` syscall places memory, well clear of programs and their stack.
+pub const USER_MAP_BASE: usize = 0x0000_1000_0000_0000;
+pub const USER_MAP_END: usize = 0x0000_7000_0000_0000;
 
 pub fn early_system_init() {
     if SYSTEM_INIT_DONE
@@ -72,6 +88,7 @@ pub fn early_system_init() {
 }
 
 pub unsafe fn early_cpu_init() {
+    PerCpu::enter();
     cpu::use_();
     idt::load();
     lapic::init();
@@ -103,14 +120,39 @@ pub fn interrupts_are_disabled() -> bool {
     }
 }
 
-pub fn send_ipi(cpu_id: u8, vector: u8) {
-    lapic::send_ipi(cpu_id, vector)
+pub fn send_ipi(cpu: usize, vector: u8) {
+    lapic::send_ipi(PerCpu::apic_id(cpu), vector)
 }
 
 pub fn broadcast_ipi(vector: u8) {
     lapic::broadcast_ipi(vector)
 }
 
+/// Nanoseconds since the monotonic clock started, early in boot.
+pub fn nanos_since_boot() -> u64 {
+    tsc::nanos()
+}
+
+/// Seconds since the Unix epoch, from the hardware clock.
+pub fn read_rtc() -> u64 {
+    rtc::read_unix_seconds()
+}
+
+/// Have this CPU's timer interrupt at `nanos_since_boot() == nanos`, or never.
+pub fn set_timer_deadline(nanos: Option<u64>) {
+    lapic::set_deadline(nanos)
+}
+
+pub fn disable_interrupts() {
+    unsafe { asm!("cli") };
+}
+
+/// Enable interrupts and halt until one arrives. An interrupt that's already pending
+/// still wakes the CPU, since `sti` only takes effect after the `hlt`.
+pub fn enable_interrupts_and_halt() {
+    unsafe { asm!("sti", "hlt") };
+}
+
 pub fn sleep_forever() -> ! {
     loop {
         unsafe { asm!("sti", "hlt") };
@@ -1,6 +1,8 @@
 use crate::print::{print, println};
 use crate::vmm::PageFlags;
 use crate::{pmm, x86};
+use alloc::vec;
+use alloc::vec::Vec;
 use core::arch::asm;
 use core::fmt::{Display, Formatter};
 use spin::Once;
@@ -176,9 +178,29 @@ fn print_page_table_level(root: *const PageTable, level: i32, addr: u64) {
     }
 }
 
-pub unsafe fn map_in_table(root: *mut PageTable, virt: usize, phys: u64, flags: PageFlags) {
+/// Map `virt` to `phys` in `root`. Returns false, leaving `virt` unmapped, if there was no
+/// memory for the page tables on the way to it.
+pub unsafe fn map_in_table(
+    root: *mut PageTable,
+    virt: usize,
+    phys: u64,
+    flags: PageFlags,
+) -> bool {
     let flags = generic_flags(flags) | Pte::PRESENT;
+    let Some(p1) = create_leaf_entry(root, virt) else {
+        return false;
+    };
+    let replaced = p1.is_present();
+    p1.set(phys, flags);
+    if replaced {
+        flush_tlb(virt);
+    }
+    true
+}
 
+/// The last-level entry for `virt` in `root`, allocating the tables on the way to it.
+/// Tables allocated before running out of memory are left for `free_tree`.
+unsafe fn create_leaf_entry(root: *mut PageTable, virt: usize) -> Option<&'static mut Pte> {
     let p4_offset = (virt >> 39) & 0x1ff;
     let p3_offset = (virt >> 30) & 0x1ff;
     let p2_offset = (virt >> 21) & 0x1ff;
@@ -192,7 +214,7 @@ pub unsafe fn map_in_table(root: *mut PageTable, virt: usize, phys: u64, flags:
 
     let p4 = &mut (*root).entries[p4_offset];
     if !p4.is_present() {
-        let p3_page = pmm::alloc().unwrap();
+        let p3_page = pmm::alloc(pmm::Owner::PageTable)?;
         let p3_ptr = x86::direct_map_offset(p3_page) as *mut PageTable;
         p4.set(p3_page, table_flags);
         for entry in (*p3_ptr).entries.iter_mut() {
@@ -202,7 +224,7 @@ pub unsafe fn map_in_table(root: *mut PageTable, virt: usize, phys: u64, flags:
 
     let p3 = &mut (*p4.next_table_mut()).entries[p3_offset];
     if !p3.is_present() {
-        let p2_page = pmm::alloc().unwrap();
+        let p2_page = pmm::alloc(pmm::Owner::PageTable)?;
         let p2_ptr = x86::direct_map_offset(p2_page) as *mut PageTable;
         p3.set(p2_page, table_flags);
         for entry in (*p2_ptr).entries.iter_mut() {
@@ -215,7 +237,7 @@ pub unsafe fn map_in_table(root: *mut PageTable, virt: usize, phys: u64, flags:
 
     let p2 = &mut (*p3.next_table_mut()).entries[p2_offset];
     if !p2.is_present() {
-        let p1_page = pmm::alloc().unwrap();
+        let p1_page = pmm::alloc(pmm::Owner::PageTable)?;
         let p1_ptr = x86::direct_map_offset(p1_page) as *mut PageTable;
         p2.set(p1_page, table_flags);
         for entry in (*p1_ptr).entries.iter_mut() {
@@ -226,8 +248,94 @@ pub unsafe fn map_in_table(root: *mut PageTable, virt: usize, phys: u64, flags:
         panic!("tried to map inside a huge page")
     }
 
-    let p1 = &mut (*p2.next_table_mut()).entries[p1_offset];
-    p1.set(phys, flags);
+    Some(&mut (*p2.next_table_mut()).entries[p1_offset])
+}
+
+/// The last-level entry mapping `virt` in `root`, if its tables exist and it isn't inside
+/// a huge page.
+unsafe fn leaf_entry(root: *mut PageTable, virt: usize) -> Option<&'static mut Pte> {
+    let p4 = &mut (*root).entries[(virt >> 39) & 0x1ff];
+    if !p4.is_present() {
+        return None;
+    }
+    let p3 = &mut (*p4.next_table_mut()).entries[(virt >> 30) & 0x1ff];
+    if !p3.is_present() || p3.is_huge() {
+        return None;
+    }
+    let p2 = &mut (*p3.next_table_mut()).entries[(virt >> 21) & 0x1ff];
+    if !p2.is_present() || p2.is_huge() {
+        return None;
+    }
+    Some(&mut (*p2.next_table_mut()).entries[(virt >> 12) & 0x1ff])
+}
+
+fn flush_tlb(virt: usize) {
+    unsafe { asm!("invlpg [{}]", in(reg) virt) };
+}
+
+/// Remove the mapping of `virt` from `root` and return the page it mapped. The tables
+/// are left in place for `free_tree`.
+pub unsafe fn unmap_in_table(root: *mut PageTable, virt: usize) -> Option<u64> {
+    let p1 = leaf_entry(root, virt)?;
+    if !p1.is_present() {
+        return None;
+    }
+    let phys = p1.address();
+    p1.set(0, 0);
+    flush_tlb(virt);
+    Some(phys)
+}
+
+/// Change the flags of the mapping of `virt` in `root`. Returns whether there was one.
+pub unsafe fn protect_in_table(root: *mut PageTable, virt: usize, flags: PageFlags) -> bool {
+    let Some(p1) = leaf_entry(root, virt) else {
+        return false;
+    };
+    if !p1.is_present() {
+        return false;
+    }
+    let mut flags = generic_flags(flags);
+    if p1.is_copy_on_write() {
+        // Still shared, so it stays read-only until the write fault that copies it
+        flags = flags & !Pte::WRITEABLE | Pte::COPY_ON_WRITE;
+    }
+    p1.set_flags(flags);
+    flush_tlb(virt);
+    true
+}
+
+/// Map the page mapped at `virt` in `root`, if there is one, at the same place in `child`
+/// and take another reference to it. With `copy_on_write` neither side may write to it
+/// any more, and the first write faults so the writer can take its own copy. Returns
+/// false, leaving `root` as it was, if there was no memory for `child`'s page tables.
+pub unsafe fn share_in_table(
+    root: *mut PageTable,
+    child: *mut PageTable,
+    virt: usize,
+    copy_on_write: bool,
+) -> bool {
+    let Some(p1) = leaf_entry(root, virt) else {
+        return true;
+    };
+    if !p1.is_present() {
+        return true;
+    }
+    let Some(child_p1) = create_leaf_entry(child, virt) else {
+        return false;
+    };
+    if copy_on_write && !p1.is_copy_on_write() {
+        p1.set_flags(p1.flags() & !Pte::WRITEABLE | Pte::COPY_ON_WRITE);
+        flush_tlb(virt);
+    }
+    *child_p1 = *p1;
+    pmm::add_ref(p1.address());
+    true
+}
+
+/// The page mapped copy-on-write at `virt` in `root`, if there is one.
+pub unsafe fn copy_on_write_page(root: *mut PageTable, virt: usize) -> Option<u64> {
+    let p1 = leaf_entry(root, virt)?;
+    p1.is_copy_on_write().then(|| p1.address())
 }
 
 pub fn physical_address(virtual_address: usize) -> Option<u64> {
@@ -265,10 +373,55 @@ pub fn physical_address(virtual_address: usize) -> Option<u64> {
     Some(p1.address() + (virtual_address as u64 & Pte::P1_OFFSET))
 }
 
-pub fn new_tree() -> *mut PageTable {
+/// Like `physical_address`, but walks `root` instead of the active tree and only succeeds
+/// if every level of the mapping allows usermode access (and writes, if `write` is set).
+pub fn user_physical_address(
+    root: *const PageTable,
+    virtual_address: usize,
+    write: bool,
+) -> Option<u64> {
+    let allowed = |entry: &Pte| entry.is_usermode() && (!write || entry.is_writeable());
+
+    let p4_offset = (virtual_address >> 39) & 0x1ff;
+    let p3_offset = (virtual_address >> 30) & 0x1ff;
+    let p2_offset = (virtual_address >> 21) & 0x1ff;
+    let p1_offset = (virtual_address >> 12) & 0x1ff;
+
+    let p4 = unsafe { &(*root).entries[p4_offset] };
+    if !allowed(p4) {
+        return None;
+    }
+
+    let p3 = unsafe { &(*p4.next_table()).entries[p3_offset] };
+    if !allowed(p3) {
+        return None;
+    }
+    if p3.is_huge() {
+        return Some(p3.address() + (virtual_address as u64 & Pte::P3_OFFSET));
+    }
+
+    let p2 = unsafe { &(*p3.next_table()).entries[p2_offset] };
+    if !allowed(p2) {
+        return None;
+    }
+    if p2.is_huge() {
+        return Some(p2.address() + (virtual_address as u64 & Pte::P2_OFFSET));
+    }
+
+    let p1 = unsafe { &(*p2.next_table()).entries[p1_offset] };
+    if !allowed(p1) {
+        return None;
+    }
+
+    Some(p1.address() + (virtual_address as u64 & Pte::P1_OFFSET))
+}
+
+/// A new tree sharing the kernel half of the active one, or `None` if there's no memory
+/// for its root.
+pub fn new_tree() -> Option<*mut PageTable> {
     let root = get_vm_root();
 
-    let page = pmm::alloc().unwrap();
+    let page = pmm::alloc(pmm::Owner::PageTable)?;
     let page = x86::direct_map_offset(page) as *mut PageTable;
     unsafe {
         for (i, entry) in (*page).entries.iter_mut().enumerate() {
@@ -279,7 +432,7 @@ pub fn new_tree() -> *mut PageTable {
             }
         }
     }
-    page
+    Some(page)
 }
 
 pub fn load_tree(root: *const PageTable) {
@@ -316,6 +469,25 @@ unsafe fn free_tree_level(root: *mut PageTable, level: usize) {
     }
 }
 
+/// The physical addresses of every page table in the kernel's tree, sorted. Processes
+/// share all but the root of these.
+pub fn kernel_table_pages() -> Vec<u64> {
+    let root = *KERNEL_ROOT.get().unwrap() as *const PageTable;
+    let mut pages = vec![x86::physical_address(root as usize).unwrap()];
+    unsafe { table_pages_level(root, 4, &mut pages) };
+    pages.sort_unstable();
+    pages
+}
+
+unsafe fn table_pages_level(root: *const PageTable, level: usize, pages: &mut Vec<u64>) {
+    for entry in (*root).entries.iter() {
+        if level > 1 && entry.is_present() && !entry.is_huge() {
+            pages.push(entry.address());
+            table_pages_level(entry.next_table(), level - 1, pages);
+        }
+    }
+}
+
 static KERNEL_ROOT: Once<usize> = Once::new();
 
 pub unsafe fn init() {
@@ -0,0 +1,28 @@
+//! The legacy PIT, used only as a known clock to calibrate the other timers against.
+//! Channel 2 can be started and polled through port 0x61 without raising interrupts.
+
+use crate::x86::pio;
+
+const CHANNEL_2_DATA: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+const GATE: u16 = 0x61;
+
+pub const FREQUENCY: u64 = 1_193_182;
+
+/// Busy-wait for `count` PIT cycles, at most `u16::MAX` (about 55ms).
+pub unsafe fn wait_cycles(count: u16) {
+    // Gate off and speaker off, so the channel doesn't count until we're ready
+    let gate = pio::read_u8(GATE) & !0x03;
+    pio::write_u8(GATE, gate);
+
+    pio::write_u8(COMMAND, 0b1011_0000); // channel 2, lobyte/hibyte, mode 0
+    pio::write_u8(CHANNEL_2_DATA, count as u8);
+    pio::write_u8(CHANNEL_2_DATA, (count >> 8) as u8);
+
+    pio::write_u8(GATE, gate | 0x01); // start counting
+    while pio::read_u8(GATE) & 0x20 == 0 {
+        core::hint::spin_loop();
+    }
+
+    pio::write_u8(GATE, gate);
+}
@@ -0,0 +1,79 @@
+//! The CMOS real-time clock, read once at boot to set the realtime clock.
+
+use crate::x86::pio;
+use cardinal3_interface::calendar::days_from_civil;
+
+const INDEX: u16 = 0x70;
+const DATA: u16 = 0x71;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+
+const UPDATE_IN_PROGRESS: u8 = 0x80;
+const HOURS_24: u8 = 0x02;
+const BINARY: u8 = 0x04;
+const HOUR_PM: u8 = 0x80;
+
+unsafe fn read(register: u8) -> u8 {
+    pio::write_u8(INDEX, register);
+    pio::read_u8(DATA)
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+struct Raw([u8; 6]);
+
+unsafe fn read_raw() -> Raw {
+    while read(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    Raw([SECONDS, MINUTES, HOURS, DAY, MONTH, YEAR].map(|register| read(register)))
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+/// Seconds since the Unix epoch, according to the RTC. The RTC is assumed to be in UTC
+/// and in the 21st century, since the century register isn't reliably present.
+pub fn read_unix_seconds() -> u64 {
+    let (raw, status) = unsafe {
+        // Read until two reads agree, so an update can't tear the result
+        let mut raw = read_raw();
+        loop {
+            let again = read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        (raw.0, read(STATUS_B))
+    };
+
+    let [mut seconds, mut minutes, hours, mut day, mut month, mut year] = raw;
+    let pm = hours & HOUR_PM != 0;
+    let mut hours = hours & !HOUR_PM;
+    if status & BINARY == 0 {
+        seconds = from_bcd(seconds);
+        minutes = from_bcd(minutes);
+        hours = from_bcd(hours);
+        day = from_bcd(day);
+        month = from_bcd(month);
+        year = from_bcd(year);
+    }
+    if status & HOURS_24 == 0 {
+        hours %= 12;
+        if pm {
+            hours += 12;
+        }
+    }
+
+    let days = days_from_civil(2000 + year as i64, month as u64, day as u64);
+    let seconds_of_day = hours as i64 * 3600 + minutes as i64 * 60 + seconds as i64;
+    (days * 86_400 + seconds_of_day).max(0) as u64
+}
@@ -1,17 +1,14 @@
+use crate::sync::WaitQueue;
 use crate::x86::pio;
 use alloc::collections::VecDeque;
-use alloc::vec::Vec;
 use core::fmt::Write;
-use core::future::Future;
-use core::pin::Pin;
-use core::task::{Context, Poll, Waker};
 use spin::{Lazy, Mutex, MutexGuard};
 
 pub struct SerialPort {
     port: u16,
     writer: Mutex<SerialPortWriter>,
     queue: Mutex<VecDeque<u8>>,
-    futures: Mutex<Vec<Waker>>,
+    readers: WaitQueue,
 }
 
 impl SerialPort {
@@ -30,7 +27,7 @@ impl SerialPort {
         let mut sp = SerialPort {
             port,
             queue: Mutex::new(VecDeque::new()),
-            futures: Mutex::new(Vec::new()),
+            readers: WaitQueue::new(),
             writer: Mutex::new(SerialPortWriter { port }),
         };
         sp.init();
@@ -42,9 +39,7 @@ impl SerialPort {
             let b = pio::read_u8(self.port);
             self.queue.lock().push_back(b);
         }
-        for waker in self.futures.lock().drain(..) {
-            waker.wake();
-        }
+        self.readers.wake_all();
     }
 
     pub fn write(&self) -> MutexGuard<SerialPortWriter> {
@@ -56,8 +51,14 @@ impl SerialPort {
         self.writer.try_lock()
     }
 
-    pub fn read(&self) -> SerialPortReadFuture {
-        SerialPortReadFuture
+    /// Wait for the next byte from the port.
+    pub async fn read(&self) -> u8 {
+        let b = self.readers.wait_until(|| self.queue.lock().pop_front()).await;
+        match b {
+            b'\r' => b'\n',
+            0x7f => 0x08,
+            b => b,
+        }
     }
 }
 
@@ -86,24 +87,4 @@ impl Write for SerialPortWriter {
     }
 }
 
-#[must_use = "futures do nothing unless you `.await` or poll them"]
-pub struct SerialPortReadFuture;
-
-impl Future for SerialPortReadFuture {
-    type Output = u8;
-
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let mut queue = SERIAL.queue.lock();
-        match queue.pop_front() {
-            Some(b'\r') => Poll::Ready(b'\n'),
-            Some(0x7f) => Poll::Ready(0x08),
-            Some(b) => Poll::Ready(b),
-            None => {
-                SERIAL.futures.lock().push(cx.waker().clone());
-                Poll::Pending
-            }
-        }
-    }
-}
-
 pub static SERIAL: Lazy<SerialPort> = Lazy::new(|| unsafe { SerialPort::new(0x3F8) });
@@ -0,0 +1,40 @@
+//! The TSC as the kernel's monotonic clock. Its frequency is measured when the LAPIC
+//! timer is calibrated, and it's assumed to be in step across CPUs. Without an invariant
+//! TSC it may also change speed with power states, so then it's only used as the clock
+//! and timers count down on the LAPIC instead.
+
+use crate::x86::{cpu, rdtsc};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static BOOT: AtomicU64 = AtomicU64::new(0);
+
+pub fn init(hz: u64) {
+    BOOT.store(rdtsc(), Ordering::Relaxed);
+    FREQUENCY.store(hz, Ordering::Release);
+}
+
+/// Whether the CPU says its TSC ticks at a constant rate in every power state.
+pub fn invariant() -> bool {
+    cpu::cpuid(0x8000_0000, 0)[0] >= 0x8000_0007 && cpu::cpuid(0x8000_0007, 0)[3] & 1 << 8 != 0
+}
+
+pub fn frequency() -> u64 {
+    FREQUENCY.load(Ordering::Acquire)
+}
+
+/// Nanoseconds since `init`, or 0 before it.
+pub fn nanos() -> u64 {
+    let hz = frequency();
+    if hz == 0 {
+        return 0;
+    }
+    let elapsed = rdtsc().saturating_sub(BOOT.load(Ordering::Relaxed));
+    (elapsed as u128 * 1_000_000_000 / hz as u128) as u64
+}
+
+/// The TSC value `nanos` after `init`.
+pub fn at_nanos(nanos: u64) -> u64 {
+    let offset = nanos as u128 * frequency() as u128 / 1_000_000_000;
+    BOOT.load(Ordering::Relaxed) + offset as u64
+}
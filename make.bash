@@ -15,6 +15,7 @@ build_dir="$(pwd)/build"
 
 cargo -Zunstable-options -C userland build
 cp userland/target/x86_64-unknown-none/debug/user_main "$build_dir"/userland
+cp userland/target/x86_64-unknown-none/debug/echo "$build_dir"/echo
 
 
 cargo -Zunstable-options -C kernel build
@@ -31,6 +32,7 @@ make -C limine
 
 cp ./"$kernel_file" isodir/boot
 cp ./userland isodir/boot
+cp ./echo isodir/boot
 cp ../kernel/limine.cfg isodir/boot/limine/
 cp ./limine/limine.sys ./limine/limine-cd.bin ./limine/limine-cd-efi.bin \
     isodir/boot/limine/
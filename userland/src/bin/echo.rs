@@ -0,0 +1,15 @@
+#![no_std]
+#![no_main]
+
+use cardinal3_userland::{env, print, println};
+
+#[no_mangle]
+fn cardinal_main() {
+    for (i, arg) in env::args().skip(1).enumerate() {
+        if i > 0 {
+            print!(" ");
+        }
+        print!("{}", arg);
+    }
+    println!();
+}
@@ -1,23 +1,140 @@
 #![no_std]
 #![no_main]
 
-use cardinal3_interface::Syscall;
-use cardinal3_userland::{executor, println, syscall};
+extern crate alloc;
+
+use alloc::vec;
+use cardinal3_interface::{MapFlags, Priority, Syscall};
+use cardinal3_userland::time::{Instant, SystemTime};
+use cardinal3_userland::{env, executor, println, syscall};
 
 #[no_mangle]
-fn cardinal_main(_arg: usize) {
+fn cardinal_main() {
     println!("Hello World (from cardinal_main)");
+    for (i, arg) in env::args().enumerate() {
+        println!("argv[{}]: {}", i, arg);
+    }
+    println!("running with {} CPUs", syscall::cpu_count().unwrap());
+    println!("the time is {}", SystemTime::now());
+    let memory = syscall::memory_info().unwrap();
+    println!(
+        "memory: {} of {} KiB in use",
+        memory.in_use() * memory.page_size / 1024,
+        memory.total * memory.page_size / 1024
+    );
+    memory_demo();
+    fork_demo();
 
     unsafe {
         executor::spawn(main());
+        executor::spawn(channel_demo());
+        executor::spawn(socket_demo());
         executor::run();
     }
 
     syscall::exit(0);
 }
 
+fn memory_demo() {
+    // More than the static heap holds, so the allocator has to map more
+    let big = vec![1u8; 4 * 1024 * 1024];
+    println!("allocated {} KiB on the heap", big.len() / 1024);
+    drop(big);
+
+    let page = syscall::map(0x1000, MapFlags::READ | MapFlags::WRITE).unwrap();
+    unsafe { page.write(42) };
+    syscall::protect(page, 0x1000, MapFlags::READ).unwrap();
+    println!("mapped a page at {:?} holding {}", page, unsafe { page.read() });
+    syscall::unmap(page, 0x1000).unwrap();
+}
+
+fn fork_demo() {
+    let mut values = vec![1u64; 1024];
+    let child = syscall::fork().unwrap();
+    if child == 0 {
+        // Only this process's copy of the page changes
+        values[0] = 2;
+        println!("forked child sees {}", values[0]);
+        syscall::exit(values[0]);
+    }
+
+    unsafe {
+        executor::spawn(async move {
+            let code = syscall::wait(child).await.unwrap();
+            println!("forked child exited with {}, parent still sees {}", code, values[0]);
+            syscall::close(child).unwrap();
+        });
+    }
+}
+
 async fn main() {
-    executor::syscall(Syscall::Print("Hello world from async 1!\n")).await;
-    executor::syscall(Syscall::Sleep(1_000_000)).await;
-    executor::syscall(Syscall::Print("Hello world from async 2!\n")).await;
+    executor::syscall(Syscall::Print("Hello world from async 1!\n")).await.unwrap();
+    let start = Instant::now();
+    syscall::sleep(1_000_000).await.unwrap();
+    println!("slept for {:?}", start.elapsed());
+    executor::syscall(Syscall::Print("Hello world from async 2!\n")).await.unwrap();
+
+    let echo = syscall::spawn("/boot/echo", &["hello", "from", "echo"]).await.unwrap();
+    println!("spawned echo as handle {}", echo);
+    syscall::set_priority(echo, Priority::Idle).unwrap();
+    let code = syscall::wait(echo).await.unwrap();
+    println!("echo exited with {}", code);
+    syscall::close(echo).unwrap();
+
+    if let Err(err) = syscall::spawn("/boot/nonexistent", &[]).await {
+        println!("spawning a missing program failed as expected: {:?}", err);
+    }
+
+    if let Err(err) = syscall::dg_close(0).await {
+        println!("dg_close(0) failed as expected: {:?}", err);
+    }
+
+    let socket = syscall::dg_socket().await.unwrap();
+    let copy = syscall::duplicate(socket).unwrap();
+    syscall::dg_close(socket).await.unwrap();
+    syscall::dg_close(copy).await.unwrap();
+    if let Err(err) = syscall::dg_close(copy).await {
+        println!("closing a closed handle failed as expected: {:?}", err);
+    }
+}
+
+async fn channel_demo() {
+    let (a, b) = syscall::channel_create().await.unwrap();
+
+    unsafe {
+        executor::spawn(async move {
+            let mut buf = [0u8; 64];
+            let size = syscall::channel_recv(b, &mut buf, &mut []).await.unwrap();
+            let message = core::str::from_utf8(&buf[..size.bytes as usize]).unwrap();
+            println!("channel received: {}", message);
+            syscall::channel_close(b).await.unwrap();
+        });
+    }
+
+    syscall::sleep(100_000).await.unwrap();
+    syscall::channel_send(a, b"hello over a channel", &[]).await.unwrap();
+    syscall::channel_close(a).await.unwrap();
+}
+
+async fn socket_demo() {
+    const ADDR: u64 = 7;
+
+    let server = syscall::dg_socket().await.unwrap();
+    syscall::dg_bind(server, ADDR).await.unwrap();
+
+    unsafe {
+        executor::spawn(async move {
+            let mut buf = [0u8; 64];
+            let len = syscall::dg_read(server, &mut buf).await.unwrap();
+            let len = (len as usize).min(buf.len());
+            let message = core::str::from_utf8(&buf[..len]).unwrap();
+            println!("socket received: {}", message);
+            syscall::dg_close(server).await.unwrap();
+        });
+    }
+
+    let client = syscall::dg_socket().await.unwrap();
+    syscall::sleep(100_000).await.unwrap();
+    syscall::dg_write(client, ADDR, b"hello over a socket").await.unwrap();
+    syscall::dg_close(client).await.unwrap();
 }
@@ -0,0 +1,67 @@
+//! Arguments, environment and auxiliary vector the kernel passed on the initial stack.
+
+use cardinal3_interface::AuxType;
+use core::ffi::{c_char, CStr};
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+static INITIAL_STACK: AtomicPtr<u64> = AtomicPtr::new(null_mut());
+
+pub(crate) unsafe fn init(argc: *mut u64) {
+    INITIAL_STACK.store(argc, Ordering::Relaxed);
+}
+
+fn argc_ptr() -> *const u64 {
+    let argc = INITIAL_STACK.load(Ordering::Relaxed);
+    assert!(!argc.is_null(), "env used before _start");
+    argc
+}
+
+/// Iterate over a null-terminated array of string pointers starting at `ptr`.
+unsafe fn strings(ptr: *const u64) -> impl Iterator<Item = &'static str> {
+    (0..)
+        .map(move |i| *ptr.add(i) as *const c_char)
+        .take_while(|s| !s.is_null())
+        .map(|s| CStr::from_ptr(s).to_str().expect("kernel passed a non-UTF-8 string"))
+}
+
+fn argv_ptr() -> *const u64 {
+    unsafe { argc_ptr().add(1) }
+}
+
+fn envp_ptr() -> *const u64 {
+    let argc = unsafe { *argc_ptr() } as usize;
+    unsafe { argv_ptr().add(argc + 1) }
+}
+
+fn auxv_ptr() -> *const u64 {
+    let envc = unsafe { strings(envp_ptr()) }.count();
+    unsafe { envp_ptr().add(envc + 1) }
+}
+
+/// The program's arguments. The first is the path it was started from.
+pub fn args() -> impl Iterator<Item = &'static str> {
+    unsafe { strings(argv_ptr()) }
+}
+
+/// The program's environment, as `KEY=value` strings.
+pub fn env() -> impl Iterator<Item = &'static str> {
+    unsafe { strings(envp_ptr()) }
+}
+
+/// Look up `key` in the environment.
+pub fn var(key: &str) -> Option<&'static str> {
+    env().find_map(|entry| {
+        let (k, v) = entry.split_once('=')?;
+        (k == key).then_some(v)
+    })
+}
+
+/// Look up an entry in the auxiliary vector.
+pub fn aux(typ: AuxType) -> Option<u64> {
+    let auxv = auxv_ptr();
+    (0..)
+        .map(|i| unsafe { (*auxv.add(2 * i), *auxv.add(2 * i + 1)) })
+        .take_while(|&(t, _)| t != AuxType::Null as u64)
+        .find_map(|(t, value)| (t == typ as u64).then_some(value))
+}
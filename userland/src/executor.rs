@@ -2,7 +2,7 @@ use crate::syscall::syscall_future;
 use alloc::boxed::Box;
 use alloc::collections::{BTreeMap, VecDeque};
 use alloc::sync::Arc;
-use cardinal3_interface::{Syscall, SyscallReturn};
+use cardinal3_interface::{Error, Syscall, SyscallReturn};
 use core::future::Future;
 use core::pin::Pin;
 use core::sync::atomic::{AtomicU64, Ordering};
@@ -70,7 +70,7 @@ struct SyscallFuture<'a> {
 }
 
 impl Future for SyscallFuture<'_> {
-    type Output = SyscallReturn;
+    type Output = Result<u64, Error>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let task_id = unsafe { &*(cx.waker().data() as *const WakerData) }.task_id;
@@ -78,14 +78,13 @@ impl Future for SyscallFuture<'_> {
         let result = unsafe { EXECUTOR.dispatch_syscall(task_id, &self.syscall_args) };
 
         match result {
-            SyscallReturn::Complete(_) => Poll::Ready(result),
             SyscallReturn::NotComplete => Poll::Pending,
-            SyscallReturn::Error(_) => Poll::Ready(result),
+            result => Poll::Ready(result.into()),
         }
     }
 }
 
-pub fn syscall<'a>(args: Syscall<'a>) -> impl Future<Output = SyscallReturn> + 'a {
+pub fn syscall<'a>(args: Syscall<'a>) -> impl Future<Output = Result<u64, Error>> + 'a {
     SyscallFuture { syscall_args: args }
 }
 
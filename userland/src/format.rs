@@ -5,8 +5,7 @@ pub struct SyscallPrint;
 
 impl Write for SyscallPrint {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        syscall::print(s);
-        Ok(())
+        syscall::print(s).map(|_| ()).map_err(|_| core::fmt::Error)
     }
 }
 
@@ -24,7 +23,7 @@ macro_rules! print {
 
 #[macro_export]
 macro_rules! println {
-    () => ();
+    () => ($crate::format::_print(format_args!("\n")));
     ($fmt:expr) => ($crate::format::_print(format_args!(concat!($fmt, "\n"))));
     ($fmt:expr, $($arg:tt)*) => ($crate::format::_print(format_args!(concat!($fmt, "\n"), $($arg)*)));
 }
\ No newline at end of file
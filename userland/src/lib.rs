@@ -5,17 +5,37 @@ extern crate alloc;
 
 pub use cardinal3_allocator as allocator;
 
+use cardinal3_interface::MapFlags;
+use core::ptr::NonNull;
+
+pub mod env;
 pub mod executor;
 pub mod format;
 pub mod syscall;
+pub mod time;
 
 #[global_allocator]
 static ALLOCATOR: allocator::linky::LockedAllocator = allocator::linky::new();
 
+/// The least the heap grows by at a time once its static memory is used up.
+const HEAP_GROWTH: usize = 0x100000;
+
 pub fn static_heap_init() {
     unsafe {
         ALLOCATOR.init();
     }
+    ALLOCATOR.set_grow(grow_heap);
+    ALLOCATOR.set_shrink(shrink_heap);
+}
+
+fn grow_heap(min_len: usize) -> Option<(NonNull<u8>, usize)> {
+    let len = min_len.max(HEAP_GROWTH).next_multiple_of(0x1000);
+    let addr = syscall::map_heap(len, MapFlags::READ | MapFlags::WRITE).ok()?;
+    Some((NonNull::new(addr)?, len))
+}
+
+fn shrink_heap(addr: NonNull<u8>, len: usize) {
+    syscall::unmap_heap(addr.as_ptr(), len).expect("failed to unmap heap memory");
 }
 
 #[panic_handler]
@@ -29,17 +49,32 @@ fn panic(panic_info: &core::panic::PanicInfo) -> ! {
 }
 
 extern "Rust" {
-    fn cardinal_main(arg: usize);
+    fn cardinal_main();
 }
 
 static mut N: usize = 0;
 
+/// Entry point of every user program.
+///
+/// # Safety
+///
+/// Only the kernel may call this, with `argc` pointing at the initial stack described in
+/// `cardinal3_interface`.
 #[no_mangle]
-pub extern "C" fn _start(arg: usize) {
+pub unsafe extern "C" fn _start(argc: *mut u64) {
     static_heap_init();
+    unsafe { env::init(argc) };
+    let abi_version = syscall::abi_version().expect("kernel does not report an ABI version");
+    if abi_version != cardinal3_interface::ABI_VERSION {
+        panic!(
+            "kernel syscall ABI version {} does not match ours ({})",
+            abi_version,
+            cardinal3_interface::ABI_VERSION,
+        );
+    }
     println!("userland started..., N is {}", unsafe { N },);
     unsafe {
-        cardinal_main(arg);
+        cardinal_main();
     }
     println!("main returned!");
     syscall::exit(0);
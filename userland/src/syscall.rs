@@ -1,4 +1,8 @@
-use cardinal3_interface::{Syscall, SyscallReturn};
+use alloc::vec::Vec;
+use cardinal3_interface::{
+    Clock, Error, MapFlags, MemoryInfo, Priority, RecvSize, ReturnKind, StrRef, Syscall,
+    SyscallReturn,
+};
 use core::arch::asm;
 use crate::executor;
 
@@ -7,35 +11,199 @@ pub(crate) fn syscall_future(
     task_id: u64,
     tasks_to_wake: &mut [u64],
 ) -> (SyscallReturn, usize) {
+    let args = args.encode();
     let return_type: u64;
     let return_value: u64;
     let wake_count: usize;
     unsafe {
         asm!(
             "int 0x80",
-            inout("rax") args as *const _ => return_type,
+            inout("rax") args.number => return_type,
             inout("rdi") task_id => return_value,
             in("rsi") tasks_to_wake.as_mut_ptr(),
             inout("rdx") tasks_to_wake.len() => wake_count,
+            in("r8") args.args[0],
+            in("r9") args.args[1],
+            in("r10") args.args[2],
+            in("r11") args.args[3],
+            in("r12") args.args[4],
+            in("r13") args.args[5],
             options(nostack)
         );
     }
     (
-        match return_type {
-            0 => SyscallReturn::Complete(return_value),
-            1 => SyscallReturn::NotComplete,
-            2 => todo!("Syscall error not implemented"),
-            _ => panic!("Invalid syscall return"),
+        match ReturnKind::try_from(return_type) {
+            Ok(ReturnKind::Complete) => SyscallReturn::Complete(return_value),
+            Ok(ReturnKind::NotComplete) => SyscallReturn::NotComplete,
+            Ok(ReturnKind::Error) => match Error::try_from(return_value) {
+                Ok(error) => SyscallReturn::Error(error),
+                Err(_) => panic!("Invalid syscall error {}", return_value),
+            },
+            Err(_) => panic!("Invalid syscall return"),
         },
         wake_count,
     )
 }
 
-pub fn print(string: impl AsRef<str>) {
-    executor::dispatch_syscall(&Syscall::Print(string.as_ref()));
+pub fn abi_version() -> Result<u64, Error> {
+    executor::dispatch_syscall(&Syscall::AbiVersion).into()
+}
+
+pub fn print(string: impl AsRef<str>) -> Result<u64, Error> {
+    executor::dispatch_syscall(&Syscall::Print(string.as_ref())).into()
 }
 
 pub fn exit(code: u64) -> ! {
     executor::dispatch_syscall(&Syscall::Exit(code));
     unreachable!();
 }
+
+/// Close `handle`. The object behind it is closed once no handles to it are left.
+pub fn close(handle: u64) -> Result<u64, Error> {
+    executor::dispatch_syscall(&Syscall::Close(handle)).into()
+}
+
+/// Set the scheduling class of the process behind `process`, or of this process if
+/// `process` is 0.
+pub fn set_priority(process: u64, priority: Priority) -> Result<u64, Error> {
+    executor::dispatch_syscall(&Syscall::SetPriority(process, priority)).into()
+}
+
+/// Restrict the process behind `process`, or this process if `process` is 0, to the CPUs
+/// whose bits are set in `cpus`.
+pub fn set_affinity(process: u64, cpus: u64) -> Result<u64, Error> {
+    executor::dispatch_syscall(&Syscall::SetAffinity(process, cpus)).into()
+}
+
+/// The number of CPUs processes can run on.
+pub fn cpu_count() -> Result<u64, Error> {
+    executor::dispatch_syscall(&Syscall::CpuCount).into()
+}
+
+/// Read `clock`, in nanoseconds. See [`crate::time`] for friendlier types.
+pub fn clock_get(clock: Clock) -> Result<u64, Error> {
+    executor::dispatch_syscall(&Syscall::ClockGet(clock)).into()
+}
+
+/// How physical memory is being used.
+pub fn memory_info() -> Result<MemoryInfo, Error> {
+    let mut info = MemoryInfo::default();
+    Result::<u64, Error>::from(executor::dispatch_syscall(&Syscall::MemoryInfo(&mut info)))?;
+    Ok(info)
+}
+
+/// Map at least `len` bytes of zeroed memory, rounded up to whole pages.
+pub fn map(len: usize, flags: MapFlags) -> Result<*mut u8, Error> {
+    let addr: Result<u64, Error> =
+        executor::dispatch_syscall(&Syscall::Map(len as u64, flags)).into();
+    Ok(addr? as *mut u8)
+}
+
+/// Make a syscall that never parks without going through the executor. Tasks it would
+/// wake stay queued in the kernel until a later syscall has room for them.
+fn syscall_now(args: &Syscall) -> SyscallReturn {
+    syscall_future(args, 0, &mut []).0
+}
+
+/// `map` for the heap allocator, which runs with the heap locked and so can't go through
+/// the executor: that may allocate to queue the tasks a syscall wakes.
+pub(crate) fn map_heap(len: usize, flags: MapFlags) -> Result<*mut u8, Error> {
+    let addr: Result<u64, Error> = syscall_now(&Syscall::Map(len as u64, flags)).into();
+    Ok(addr? as *mut u8)
+}
+
+/// `unmap` for the heap allocator, for the same reason as `map_heap`.
+pub(crate) fn unmap_heap(addr: *mut u8, len: usize) -> Result<u64, Error> {
+    syscall_now(&Syscall::Unmap(addr as u64, len as u64)).into()
+}
+
+/// Unmap the pages covering `len` bytes at `addr`, which must be page aligned.
+pub fn unmap(addr: *mut u8, len: usize) -> Result<u64, Error> {
+    executor::dispatch_syscall(&Syscall::Unmap(addr as u64, len as u64)).into()
+}
+
+/// Change the access allowed to the pages covering `len` bytes at `addr`.
+pub fn protect(addr: *mut u8, len: usize, flags: MapFlags) -> Result<u64, Error> {
+    executor::dispatch_syscall(&Syscall::Protect(addr as u64, len as u64, flags)).into()
+}
+
+/// Start a copy of this process, sharing its memory until either side writes to it.
+/// Returns a handle to the copy, and 0 in the copy itself, where only what's spawned on
+/// the executor after this returns will run.
+pub fn fork() -> Result<u64, Error> {
+    executor::dispatch_syscall(&Syscall::Fork).into()
+}
+
+/// Return a new handle to the same object as `handle`.
+pub fn duplicate(handle: u64) -> Result<u64, Error> {
+    executor::dispatch_syscall(&Syscall::Duplicate(handle)).into()
+}
+
+/// Start the program `name` with `args` following its path in `argv`, and return a
+/// handle to it.
+pub async fn spawn(name: &str, args: &[&str]) -> Result<u64, Error> {
+    let args: Vec<StrRef> = args.iter().map(|&arg| arg.into()).collect();
+    executor::syscall(Syscall::Spawn(name, &args)).await
+}
+
+/// Wait for the process behind the handle `child` to exit and return its exit code.
+pub async fn wait(child: u64) -> Result<u64, Error> {
+    executor::syscall(Syscall::Wait(child)).await
+}
+
+pub async fn sleep(usec: u64) -> Result<u64, Error> {
+    executor::syscall(Syscall::Sleep(usec)).await
+}
+
+pub async fn dg_socket() -> Result<u64, Error> {
+    executor::syscall(Syscall::DgSocket).await
+}
+
+/// Bind `socket` to the nonzero local address `addr`.
+pub async fn dg_bind(socket: u64, addr: u64) -> Result<u64, Error> {
+    executor::syscall(Syscall::DgBind(socket, addr)).await
+}
+
+/// Send `data` as one datagram to the socket bound to `dest`.
+pub async fn dg_write(socket: u64, dest: u64, data: &[u8]) -> Result<u64, Error> {
+    executor::syscall(Syscall::DgWrite(socket, dest, data)).await
+}
+
+/// Wait for the next datagram on `socket` and read it into `buf`, truncating it if it
+/// doesn't fit. Returns the datagram's full length, which is more than `buf.len()` if it
+/// was truncated.
+pub async fn dg_read(socket: u64, buf: &mut [u8]) -> Result<u64, Error> {
+    executor::syscall(Syscall::DgRead(socket, buf)).await
+}
+
+pub async fn dg_close(socket: u64) -> Result<u64, Error> {
+    executor::syscall(Syscall::DgClose(socket)).await
+}
+
+/// Create a channel and return handles to both of its endpoints.
+pub async fn channel_create() -> Result<(u64, u64), Error> {
+    let mut ids = [0; 2];
+    executor::syscall(Syscall::ChannelCreate(&mut ids)).await?;
+    Ok((ids[0], ids[1]))
+}
+
+/// Send `data` on `channel`, moving `handles` to the receiver.
+pub async fn channel_send(channel: u64, data: &[u8], handles: &[u64]) -> Result<u64, Error> {
+    executor::syscall(Syscall::ChannelSend(channel, data, handles)).await
+}
+
+/// Receive the next message on `channel` into `buf`, and any handles sent with it into
+/// `handles`.
+pub async fn channel_recv(
+    channel: u64,
+    buf: &mut [u8],
+    handles: &mut [u64],
+) -> Result<RecvSize, Error> {
+    executor::syscall(Syscall::ChannelRecv(channel, buf, handles))
+        .await
+        .map(RecvSize::from)
+}
+
+pub async fn channel_close(channel: u64) -> Result<u64, Error> {
+    executor::syscall(Syscall::ChannelClose(channel)).await
+}
@@ -0,0 +1,114 @@
+//! Points in time, from the kernel's clocks.
+
+use crate::syscall;
+use cardinal3_interface::calendar::civil_from_days;
+use cardinal3_interface::Clock;
+use core::fmt::{Display, Formatter};
+use core::ops::{Add, Sub};
+use core::time::Duration;
+
+fn now(clock: Clock) -> Duration {
+    Duration::from_nanos(syscall::clock_get(clock).expect("clock is always readable"))
+}
+
+/// A reading of the monotonic clock, for measuring intervals. Never goes backwards.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(Duration);
+
+impl Instant {
+    pub fn now() -> Self {
+        Self(now(Clock::Monotonic))
+    }
+
+    /// Time from `earlier` to `self`, or zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.0.saturating_sub(earlier.0)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Self::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_add(duration).map(Self)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_sub(duration).map(Self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        self.checked_add(rhs).expect("overflow adding duration to instant")
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, rhs: Duration) -> Instant {
+        self.checked_sub(rhs).expect("overflow subtracting duration from instant")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+/// A reading of the realtime clock, as time since the Unix epoch. Displays as an
+/// ISO 8601 UTC timestamp.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SystemTime(Duration);
+
+pub const UNIX_EPOCH: SystemTime = SystemTime(Duration::ZERO);
+
+impl SystemTime {
+    pub const UNIX_EPOCH: SystemTime = UNIX_EPOCH;
+
+    pub fn now() -> Self {
+        Self(now(Clock::Realtime))
+    }
+
+    /// Time from `earlier` to `self`, or `None` if `earlier` is later.
+    pub fn duration_since(&self, earlier: SystemTime) -> Option<Duration> {
+        self.0.checked_sub(earlier.0)
+    }
+
+    pub fn elapsed(&self) -> Option<Duration> {
+        Self::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<SystemTime> {
+        self.0.checked_add(duration).map(Self)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<SystemTime> {
+        self.0.checked_sub(duration).map(Self)
+    }
+}
+
+impl Display for SystemTime {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let seconds = self.0.as_secs();
+        let (year, month, day) = civil_from_days(seconds / 86_400);
+        let seconds_of_day = seconds % 86_400;
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year,
+            month,
+            day,
+            seconds_of_day / 3600,
+            seconds_of_day / 60 % 60,
+            seconds_of_day % 60,
+            self.0.subsec_millis(),
+        )
+    }
+}